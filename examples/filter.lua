@@ -9,19 +9,86 @@ local exclude_patterns = {
   "exclude"
 }
 
+-- split a comma-separated string (lists from --args-json are used as is)
+local function to_list(value)
+  if type(value) == "table" then
+    return value
+  end
+  local list = {}
+  for item in string.gmatch(value, "[^,]+") do
+    list[#list + 1] = item
+  end
+  return list
+end
+
+-- override patterns with parameters, e.g.
+-- --arg include='^test/include,^test/hello' --arg exclude=exclude
+function M.setup(params)
+  if params.include then
+    include_patterns = to_list(params.include)
+  end
+  if params.exclude then
+    exclude_patterns = to_list(params.exclude)
+  end
+end
+
+-- inputs are paths, or tables with the outputs of the previous script in a pipeline
+local function input_path(input)
+  if type(input) == "table" then
+    return input.path
+  end
+  return input
+end
+
+-- output reading a file from the input dir
+local function file_output(path)
+  local state = {
+    file = nil,
+    file_handles = 0
+  }
+
+  return {
+    path = path,
+    metadata = {
+      size = io.open(path):seek("end")
+    },
+
+    open = function()
+      if state.file_handles == 0 then
+        state.file = assert(io.open(path, "r"))
+      end
+      state.file_handles = state.file_handles + 1
+    end,
+
+    close = function()
+      state.file_handles = state.file_handles - 1
+      if state.file_handles == 0 then
+        state.file:close()
+        state.file = nil
+      end
+    end,
+
+    read = function(offset, size)
+      state.file:seek("set", offset)
+      return state.file:read(size)
+    end
+  }
+end
+
 function M.transform(inputs)
   local outputs = {}
   for _, input in ipairs(inputs) do
+    local path = input_path(input)
     local matched = false
     -- must not match any exclude pattern
     for _, pattern in ipairs(exclude_patterns) do
-      if string.find(input, pattern) then
+      if string.find(path, pattern) then
         goto continue
       end
     end
     -- must match one include pattern
     for _, pattern in ipairs(include_patterns) do
-      if string.find(input, pattern) then
+      if string.find(path, pattern) then
         matched = true
         break
       end
@@ -30,41 +97,23 @@ function M.transform(inputs)
       goto continue
     end
 
-    local state = {
-      file = nil,
-      file_handles = 0
-    }
-
-    outputs[#outputs + 1] = {
-      path = input,
-      metadata = {
-        size = io.open(input):seek("end")
-      },
-
-      open = function()
-        if state.file_handles == 0 then
-          state.file = assert(io.open(input, "r"))
-        end
-        state.file_handles = state.file_handles + 1
-      end,
-
-      close = function()
-        state.file_handles = state.file_handles - 1
-        if state.file_handles == 0 then
-          state.file:close()
-          state.file = nil
-        end
-      end,
-
-      read = function(offset, size)
-        state.file:seek("set", offset)
-        return state.file:read(size)
-      end
-    }
+    if type(input) == "table" then
+      -- pass the output of the previous script through
+      outputs[#outputs + 1] = {
+        path = path,
+        metadata = {
+          size = input.size
+        },
+        open = input.open,
+        close = input.close,
+        read = input.read
+      }
+    else
+      outputs[#outputs + 1] = file_output(path)
+    end
     ::continue::
   end
   return outputs
 end
 
 return M
-
@@ -21,13 +21,59 @@ local function find_block(blocks, offset)
   end
 end
 
-local function record_line_offset(filename)
+-- inputs are paths, or tables with the outputs of the previous script in a pipeline
+local function open_input(input)
+  if type(input) == "table" then
+    input.open()
+    return {
+      size = input.size,
+      read = function(offset, size) return input.read(offset, size) end,
+      close = function() input.close() end
+    }
+  end
+  local file = assert(io.open(input, "r"))
+  return {
+    size = file:seek("end"),
+    read = function(offset, size)
+      file:seek("set", offset)
+      return file:read(size)
+    end,
+    close = function() file:close() end
+  }
+end
+
+local function read_all(source)
+  local chunks = {}
+  local offset = 0
+  while offset < source.size do
+    local chunk = source.read(offset, math.min(source.size - offset, 65536))
+    if chunk == nil or #chunk == 0 then
+      break
+    end
+    chunks[#chunks + 1] = chunk
+    offset = offset + #chunk
+  end
+  return table.concat(chunks)
+end
+
+-- lines without the newline (like io.lines)
+local function lines(input)
+  local source = open_input(input)
+  local content = read_all(source)
+  source.close()
+  if content ~= "" and string.sub(content, -1) ~= "\n" then
+    content = content .. "\n"
+  end
+  return string.gmatch(content, "(.-)\n")
+end
+
+local function record_line_offset(input)
    local line_n = 1
    local i = 1
    local src_off = 0
    local off = 0
    local blocks = {}
-   for line in io.lines(filename) do
+   for line in lines(input) do
       -- block of the line number wit a space (src_offset is nil)
       blocks[i] = {
         kind = "line_num",
@@ -57,27 +103,26 @@ local function record_line_offset(filename)
    return {
      blocks = blocks;
      file_size = off - 1;
-     -- file handle
-     file = nil;
+     -- opened input
+     source = nil;
      -- num of opened handles
      file_handles = 0;
    }
 end
 
-local function read_block(file, block, offset, size)
+local function read_block(source, block, offset, size)
   if block.kind == "line_num" then
     local data = tostring(block.line_num) .. " "
     return string.sub(data, offset + 1, offset + size)
   elseif block.kind == "file" then
-    file:seek("set", block.src_offset)
-    return file:read(size)
+    return source.read(block.src_offset + offset, size)
   else
     error("Invalid block kind: ".. block.kind)
   end
 end
 
 
-local function read_from_blocks(file, blocks, offset, size)
+local function read_from_blocks(source, blocks, offset, size)
   local i = find_block(blocks, offset)
   local b = blocks[i]
   assert(b.offset <= offset)
@@ -92,7 +137,7 @@ local function read_from_blocks(file, blocks, offset, size)
   while size > 0 and i <= #blocks do
     local s = b.size - off
     local size_to_read = math.min(s, size)
-    data = data .. read_block(file, b, off, size_to_read)
+    data = data .. read_block(source, b, off, size_to_read)
     size = size - size_to_read
     off = 0
     i = i + 1
@@ -105,8 +150,9 @@ end
 function M.transform(inputs)
   local output = {}
   for i = 1, #inputs do
-    local name = inputs[i]
-    local state = record_line_offset(name)
+    local input = inputs[i]
+    local name = type(input) == "table" and input.path or input
+    local state = record_line_offset(input)
     M.states[name] = state
 
     output[#output+1] = {
@@ -117,7 +163,7 @@ function M.transform(inputs)
 
       open = function()
         if state.file_handles == 0 then
-          state.file = assert(io.open(name, "r"))
+          state.source = open_input(input)
         end
         state.file_handles = state.file_handles + 1
       end,
@@ -125,13 +171,13 @@ function M.transform(inputs)
       close = function()
         state.file_handles = state.file_handles - 1
         if state.file_handles == 0 then
-          state.file:close()
-          state.file = nil
+          state.source.close()
+          state.source = nil
         end
       end,
 
       read = function(offset, size)
-        return read_from_blocks(state.file, state.blocks, offset, size)
+        return read_from_blocks(state.source, state.blocks, offset, size)
       end
     }
   end
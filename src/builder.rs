@@ -0,0 +1,291 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+use fuser::{BackgroundSession, MountOption, Notifier, Session};
+use crate::{lua::{LuaLoader, ScriptOptions}, pipeline::PipelineLoader, sandbox::{self, Sandbox}, transform::{StaticLoader, Transform, TransformLoader}, transformfs::{Config, OnError, SharedFs, TransformFs}, watch::{self, Background, WatchConfig}, worker::WorkerLoader};
+
+/// Stage of transforms
+enum Stage {
+  Script(PathBuf),
+  Native(Arc<dyn Transform>)
+}
+
+/// Builder to create and mount TransformFs
+#[derive(Default)]
+pub struct TransformFsBuilder {
+  /// Stages chained as a pipeline if more than one
+  stages: Vec<Stage>,
+  inputs: Vec<PathBuf>,
+  config: Config,
+  mount_options: Vec<MountOption>,
+  watch: Option<Duration>,
+  reload_on_sighup: bool
+}
+
+impl TransformFsBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Add a user Lua script as a stage
+  /// (at least one script or transform is required)
+  ///
+  /// Outputs of each stage are inputs of the next one
+  pub fn script(mut self, script: impl Into<PathBuf>) -> Self {
+    self.stages.push(Stage::Script(script.into()));
+    self
+  }
+
+  /// Add a native transform as a stage
+  pub fn transform(mut self, transform: impl Transform + 'static) -> Self {
+    self.stages.push(Stage::Native(Arc::new(transform)));
+    self
+  }
+
+  /// Input dirs/files to pass to transform (appended to previous ones)
+  pub fn inputs<I, P>(mut self, inputs: I) -> Self
+  where
+    I: IntoIterator<Item = P>,
+    P: Into<PathBuf>
+  {
+    self.inputs.extend(inputs.into_iter().map(Into::into));
+    self
+  }
+
+  pub fn config(mut self, config: Config) -> Self {
+    self.config = config;
+    self
+  }
+
+  /// Extra mount options (FSName, Subtype and RO without control dir are always set)
+  pub fn mount_options(mut self, options: Vec<MountOption>) -> Self {
+    self.mount_options = options;
+    self
+  }
+
+  /// Watch inputs and script with the debounce time when mounted
+  pub fn watch(mut self, debounce: Option<Duration>) -> Self {
+    self.watch = debounce;
+    self
+  }
+
+  /// Reload script on SIGHUP when mounted (the handler is process-wide)
+  pub fn reload_on_sighup(mut self, enabled: bool) -> Self {
+    self.reload_on_sighup = enabled;
+    self
+  }
+
+  fn loader(stages: Vec<Stage>, inputs: &[PathBuf], config: &Config) -> anyhow::Result<Box<dyn TransformLoader>> {
+    // Landlock would keep workers from being started (the program can't be executed)
+    if config.landlock && config.isolate {
+      anyhow::bail!("Landlock can't be used with isolate");
+    }
+    let sandbox = config.sandbox
+      .then(|| Sandbox::new(inputs, config.scratch_dir.as_deref()))
+      .transpose()?;
+    // the worker runs this program with the worker argument
+    let program = config.isolate.then(std::env::current_exe).transpose()?;
+    let mut loaders: Vec<Box<dyn TransformLoader>> = stages.into_iter()
+      .enumerate()
+      .map(|(i, stage)| -> Box<dyn TransformLoader> {
+        match stage {
+          Stage::Script(script) => {
+            let options = ScriptOptions {
+              // later stages always get outputs of the previous stage
+              all_entries: i == 0 && config.all_entries,
+              params: config.params.clone(),
+              lua_path: config.lua_path.clone(),
+              sandbox: sandbox.clone(),
+              time_limit: config.lua_time_limit,
+              memory_limit: config.lua_memory_limit,
+              command_timeout: config.command_timeout
+            };
+            match &program {
+              Some(program) => Box::new(WorkerLoader { program: program.clone(), script, options }),
+              None => Box::new(LuaLoader { script, options })
+            }
+          },
+          Stage::Native(t) => Box::new(StaticLoader(t))
+        }
+      })
+      .collect();
+    Ok(match loaders.len() {
+      0 => anyhow::bail!("Script or transform is required"),
+      1 => loaders.remove(0),
+      _ => Box::new(PipelineLoader { stages: loaders })
+    })
+  }
+
+  /// Load transform and run it the first time without mounting
+  pub fn build(self) -> anyhow::Result<TransformFs> {
+    let loader = Self::loader(self.stages, &self.inputs, &self.config)?;
+    TransformFs::init(self.inputs, loader, self.config)
+  }
+
+  // create session and start background threads
+  fn session(self, mount_point: &Path) -> anyhow::Result<(SharedFs, Session<SharedFs>, Background)> {
+    let Self { stages, inputs, config, mount_options, watch, reload_on_sighup } = self;
+    let scripts: Vec<PathBuf> = stages.iter()
+      .filter_map(|s| match s {
+        Stage::Script(script) => Some(script.clone()),
+        Stage::Native(_) => None
+      })
+      .collect();
+    let loader = Self::loader(stages, &inputs, &config)?;
+    // paths allowed by Landlock (script dirs are needed to load modules)
+    let landlock_paths = config.landlock.then(|| {
+      let script_dirs = scripts.iter().map(|s| match s.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from(".")
+      });
+      let read: Vec<PathBuf> = inputs.iter()
+        .chain(&config.lua_path)
+        .cloned()
+        .chain(script_dirs)
+        .collect();
+      (read, config.scratch_dir.iter().cloned().collect::<Vec<_>>())
+    });
+
+    let mut options = vec![
+      MountOption::FSName("transformfs".to_string()),
+      MountOption::Subtype("transformfs".to_string()),
+    ];
+    if !config.control_dir {
+      options.push(MountOption::RO);
+    }
+    options.extend(mount_options);
+
+    let on_error = config.on_error;
+    let fs = SharedFs::new(TransformFs::init(inputs.clone(), loader, config)?);
+    let session = Session::new(fs.clone(), mount_point, &options)?;
+    // restrict after mounting as fusermount needs full access
+    if let Some((read, write)) = landlock_paths {
+      sandbox::landlock(&read, &write)?;
+    }
+    let mut background = Background::default();
+    watch::spawn_reloader(fs.clone(), session.notifier(), reload_on_sighup, &mut background)?;
+    if on_error == OnError::Retry {
+      watch::spawn_retrier(fs.clone(), session.notifier(), &mut background);
+    }
+    if let Some(debounce) = watch {
+      watch::spawn(fs.clone(), session.notifier(), WatchConfig {
+        inputs,
+        scripts,
+        debounce
+      }, &mut background)?;
+    }
+    Ok((fs, session, background))
+  }
+
+  /// Mount and block until unmounted
+  pub fn mount(self, mount_point: impl AsRef<Path>) -> anyhow::Result<()> {
+    let (_, mut session, mut background) = self.session(mount_point.as_ref())?;
+    let result = session.run();
+    background.stop();
+    Ok(result?)
+  }
+
+  /// Mount in a background thread
+  pub fn spawn_mount(self, mount_point: impl AsRef<Path>) -> anyhow::Result<MountHandle> {
+    let (fs, session, background) = self.session(mount_point.as_ref())?;
+    let notifier = session.notifier();
+    Ok(MountHandle {
+      fs,
+      notifier,
+      background,
+      session: session.spawn()?
+    })
+  }
+}
+
+/// Handle of a background mount (unmounted when dropped)
+pub struct MountHandle {
+  fs: SharedFs,
+  notifier: Notifier,
+  /// Stopped before the session (fields are dropped in order)
+  background: Background,
+  session: BackgroundSession
+}
+
+impl MountHandle {
+  pub fn fs(&self) -> &SharedFs {
+    &self.fs
+  }
+
+  /// Rerun transform and invalidate kernel cache of changed entries
+  pub fn refresh(&self) {
+    self.fs.refresh(&self.notifier);
+  }
+
+  /// Reload script and rebuild all outputs
+  pub fn reload(&self) {
+    self.fs.reload(&self.notifier);
+  }
+
+  /// Stop background threads, unmount and wait for the session to finish
+  pub fn unmount(self) {
+    let Self { mut background, session, .. } = self;
+    background.stop();
+    session.join();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, sync::Weak};
+  use crate::{output::OutputEntry, transformfs::OnError, utils::Input};
+  use super::*;
+
+  /// Transform with no outputs
+  struct Empty {
+    /// Dropped with the transform
+    _alive: Arc<()>
+  }
+
+  impl Transform for Empty {
+    fn transform(&self, _inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+      Ok(Vec::new())
+    }
+  }
+
+  #[test]
+  fn unmount_drops_fs() {
+    // mounting needs FUSE
+    if !Path::new("/dev/fuse").exists() {
+      return;
+    }
+    let dir = std::env::temp_dir().join(format!("transformfs-unmount-{}", std::process::id()));
+    let (input, mount_point) = (dir.join("input"), dir.join("mnt"));
+    fs::create_dir_all(&input).unwrap();
+    fs::create_dir_all(&mount_point).unwrap();
+    let alive = Arc::new(());
+    let weak: Weak<()> = Arc::downgrade(&alive);
+    let handle = match TransformFsBuilder::new()
+      .transform(Empty { _alive: alive })
+      .inputs([&input])
+      .config(Config { on_error: OnError::Retry, ..Config::default() })
+      .watch(Some(Duration::from_millis(10)))
+      .reload_on_sighup(true)
+      .spawn_mount(&mount_point)
+    {
+      Ok(handle) => handle,
+      // no permission to mount in this environment
+      Err(_) => return
+    };
+    handle.unmount();
+    assert_eq!(weak.strong_count(), 0);
+  }
+}
@@ -0,0 +1,441 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{ffi::OsString, fs::{self, File}, io::{Read, Seek, SeekFrom}, os::unix::ffi::{OsStrExt, OsStringExt}, path::{Component, Path, PathBuf}, str::FromStr, sync::Arc, time::UNIX_EPOCH};
+use clap::Parser;
+use globset::{Glob, GlobMatcher};
+use regex::bytes::Regex;
+use serde::Deserialize;
+use crate::{output::{OutputContent, OutputEntry, OutputFile, OutputFileMetadata}, transform::Transform, utils::{Input, InputKind}};
+
+/// Size of each read when scanning an input
+const SCAN_CHUNK_SIZE: u32 = 1 << 20;
+
+fn parse_glob(s: &str) -> Result<GlobMatcher, globset::Error> {
+  Ok(Glob::new(s)?.compile_matcher())
+}
+
+/// Transform implemented in Rust (no script needed)
+///
+/// Parsed from a spec like `rename --regex '\.md$' --to .txt`
+#[derive(Clone, Debug, Parser, Deserialize)]
+#[command(no_binary_name = true)]
+#[serde(try_from = "String")]
+pub enum Builtin {
+  /// Keep files matching any include glob (all if none) and no exclude glob
+  Filter {
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    include: Vec<GlobMatcher>,
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    exclude: Vec<GlobMatcher>
+  },
+  /// Concatenate all files into one file
+  Concat {
+    #[arg(default_value = "output")]
+    name: PathBuf
+  },
+  /// Prepend the line number and a space to every line
+  LineNumber,
+  /// Replace the first match of the regex in paths (`$1` for capture groups)
+  Rename {
+    #[arg(long, value_name = "FROM")]
+    regex: Regex,
+    #[arg(long, value_name = "TO")]
+    to: String
+  },
+  /// Move all files under a dir
+  Prefix {
+    path: PathBuf
+  }
+}
+
+// split a spec into words (quotes group words with spaces)
+fn split_words(s: &str) -> Result<Vec<String>, String> {
+  let mut words = Vec::new();
+  let mut word: Option<String> = None;
+  let mut quote = None;
+  for c in s.chars() {
+    match (quote, c) {
+      (Some(q), c) if c == q => quote = None,
+      (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+      (None, '\'' | '"') => {
+        quote = Some(c);
+        word.get_or_insert_with(String::new);
+      },
+      (None, c) if c.is_whitespace() => words.extend(word.take()),
+      (None, c) => word.get_or_insert_with(String::new).push(c)
+    }
+  }
+  if quote.is_some() {
+    return Err(format!("unclosed quote in {:?}", s));
+  }
+  words.extend(word);
+  Ok(words)
+}
+
+impl FromStr for Builtin {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Builtin::try_parse_from(split_words(s)?).map_err(|e| e.to_string())
+  }
+}
+
+impl TryFrom<String> for Builtin {
+  type Error = String;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    s.parse()
+  }
+}
+
+/// Content of an input file (on disk or from the previous stage)
+#[derive(Clone)]
+enum Source {
+  Path(PathBuf),
+  Stage(Arc<OutputFile>)
+}
+
+impl Source {
+  fn new(input: &Input) -> Option<Self> {
+    match &input.file {
+      Some(f) => Some(Source::Stage(f.clone())),
+      None if input.kind == InputKind::File => Some(Source::Path(PathBuf::from(&input.path))),
+      None => None
+    }
+  }
+
+  fn metadata(&self) -> anyhow::Result<OutputFileMetadata> {
+    Ok(match self {
+      Source::Path(path) => {
+        let m = fs::metadata(path)?;
+        OutputFileMetadata {
+          mtime: m.modified()?.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+          ..OutputFileMetadata::new(m.len())
+        }
+      },
+      Source::Stage(f) => OutputFileMetadata {
+        size: f.size(),
+        ..f.metadata.clone()
+      }
+    })
+  }
+
+  fn read(&self, offset: u64, size: u32) -> anyhow::Result<Vec<u8>> {
+    match self {
+      Source::Path(path) => {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(size as usize);
+        file.take(size as u64).read_to_end(&mut data)?;
+        Ok(data)
+      },
+      Source::Stage(f) => (f.read)(offset, size)
+    }
+  }
+
+  // read the range fully (a read may return less than requested)
+  fn read_exact(&self, mut offset: u64, end: u64) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    while offset < end {
+      let chunk = self.read(offset, (end - offset).min(SCAN_CHUNK_SIZE as u64) as u32)?;
+      if chunk.is_empty() {
+        break;
+      }
+      offset += chunk.len() as u64;
+      data.extend(chunk);
+    }
+    Ok(data)
+  }
+
+  fn open(&self) -> anyhow::Result<()> {
+    match self {
+      Source::Stage(f) => f.open.as_ref().map_or(Ok(()), |open| open()),
+      Source::Path(_) => Ok(())
+    }
+  }
+
+  fn close(&self) -> anyhow::Result<()> {
+    match self {
+      Source::Stage(f) => f.close.as_ref().map_or(Ok(()), |close| close()),
+      Source::Path(_) => Ok(())
+    }
+  }
+
+  /// Output file with the same content
+  fn passthrough(self) -> anyhow::Result<Arc<OutputFile>> {
+    if let Source::Stage(f) = self {
+      return Ok(f);
+    }
+    let metadata = self.metadata()?;
+    Ok(Arc::new(OutputFile::new(metadata, move |offset, size| self.read(offset, size))))
+  }
+}
+
+// path of an input in output relative to its input root
+// (outputs of the previous stage are relative to the output root already)
+fn output_path(input: &Input) -> OsString {
+  let path = Path::new(&input.path);
+  let relative = match &input.root {
+    // a root that is a file keeps its name
+    Some(root) if path == root => path.file_name().map(Path::new),
+    Some(root) => path.strip_prefix(root).ok(),
+    None => None
+  };
+  // keep only normal components so that outputs stay under the output root
+  relative.unwrap_or(path)
+    .components()
+    .filter(|c| matches!(c, Component::Normal(_)))
+    .collect::<PathBuf>()
+    .into_os_string()
+}
+
+// output files with the same content and mapped paths
+fn map_paths(inputs: &[Input], f: impl Fn(&OsString) -> Option<OsString>) -> anyhow::Result<Vec<OutputEntry>> {
+  inputs.iter()
+    .filter_map(|i| Some((f(&output_path(i))?, Source::new(i)?)))
+    .map(|(path, source)| Ok(OutputEntry {
+      path,
+      content: OutputContent::File(source.passthrough()?)
+    }))
+    .collect()
+}
+
+// concatenate sources (offsets are the start of each source in output)
+fn concat(sources: Vec<(u64, Source)>, size: u64, mtime: Option<u64>) -> OutputFile {
+  let sources = Arc::new(sources);
+  let s = sources.clone();
+  OutputFile::new(OutputFileMetadata { mtime, ..OutputFileMetadata::new(size) }, move |offset, size| {
+    let end = offset.saturating_add(size as u64);
+    // last source starting at or before offset
+    let first = s.partition_point(|(start, _)| *start <= offset).saturating_sub(1);
+    let mut data = Vec::with_capacity(size as usize);
+    for (i, (start, source)) in s.iter().enumerate().skip(first) {
+      if *start >= end {
+        break;
+      }
+      let source_end = s.get(i + 1).map_or(u64::MAX, |(next, _)| *next) - start;
+      let from = offset.max(*start) - start;
+      data.extend(source.read_exact(from, (end - start).min(source_end))?);
+    }
+    Ok(data)
+  })
+  .on_open({
+    let s = sources.clone();
+    move || s.iter().try_for_each(|(_, source)| source.open())
+  })
+  .on_close(move || sources.iter().try_for_each(|(_, source)| source.close()))
+}
+
+/// Line of an input file with its line number
+struct Line {
+  /// Offset in output
+  offset: u64,
+  /// Offset in input
+  src_offset: u64,
+  /// Length in input (including the newline)
+  len: u64,
+  number: u64
+}
+
+impl Line {
+  fn prefix(&self) -> String {
+    format!("{} ", self.number)
+  }
+}
+
+// index lines of a source and return them with the output size
+fn index_lines(source: &Source) -> anyhow::Result<(Vec<Line>, u64)> {
+  let mut lines = Vec::new();
+  let (mut src_offset, mut line_start, mut out_offset) = (0u64, 0u64, 0u64);
+  let mut push = |start: u64, end: u64, lines: &mut Vec<Line>| {
+    let line = Line {
+      offset: out_offset,
+      src_offset: start,
+      len: end - start,
+      number: lines.len() as u64 + 1
+    };
+    out_offset += line.prefix().len() as u64 + line.len;
+    lines.push(line);
+  };
+  source.open()?;
+  let result = loop {
+    let chunk = match source.read(src_offset, SCAN_CHUNK_SIZE) {
+      Ok(chunk) => chunk,
+      Err(err) => break Err(err)
+    };
+    if chunk.is_empty() {
+      break Ok(());
+    }
+    for (i, _) in chunk.iter().enumerate().filter(|(_, c)| **c == b'\n') {
+      let end = src_offset + i as u64 + 1;
+      push(line_start, end, &mut lines);
+      line_start = end;
+    }
+    src_offset += chunk.len() as u64;
+  };
+  source.close()?;
+  result?;
+  // last line without newline
+  if line_start < src_offset {
+    push(line_start, src_offset, &mut lines);
+  }
+  Ok((lines, out_offset))
+}
+
+fn line_number(source: Source) -> anyhow::Result<OutputFile> {
+  let (lines, size) = index_lines(&source)?;
+  let metadata = OutputFileMetadata { size, ..source.metadata()? };
+  let s = source.clone();
+  Ok(OutputFile::new(metadata, move |offset, size| {
+    let end = offset.saturating_add(size as u64);
+    let first = lines.partition_point(|l| l.offset <= offset).saturating_sub(1);
+    let last = lines.partition_point(|l| l.offset < end);
+    let Some(needed) = lines.get(first..last).filter(|l| !l.is_empty()) else {
+      return Ok(Vec::new());
+    };
+    // read all needed lines at once
+    let src_start = needed[0].src_offset;
+    let src = s.read_exact(src_start, needed[needed.len() - 1].src_offset + needed[needed.len() - 1].len)?;
+    let mut out = Vec::new();
+    for l in needed {
+      let from = (l.src_offset - src_start) as usize;
+      out.extend(l.prefix().as_bytes());
+      out.extend(&src[from.min(src.len())..(from + l.len as usize).min(src.len())]);
+    }
+    let start = (offset - needed[0].offset) as usize;
+    Ok(out[start.min(out.len())..(start + size as usize).min(out.len())].to_vec())
+  })
+  .on_open({
+    let s = source.clone();
+    move || s.open()
+  })
+  .on_close(move || source.close()))
+}
+
+impl Transform for Builtin {
+  fn transform(&self, inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+    match self {
+      Builtin::Filter { include, exclude } => map_paths(inputs, |path| {
+        let path = Path::new(path);
+        let included = include.is_empty() || include.iter().any(|g| g.is_match(path));
+        let excluded = exclude.iter().any(|g| g.is_match(path));
+        (included && !excluded).then(|| path.as_os_str().to_os_string())
+      }),
+      Builtin::Concat { name } => {
+        let mut sources = Vec::new();
+        let mut size = 0;
+        let mut mtime = None;
+        for source in inputs.iter().filter_map(Source::new) {
+          let metadata = source.metadata()?;
+          mtime = mtime.max(metadata.mtime);
+          sources.push((size, source));
+          size += metadata.size;
+        }
+        Ok(vec![OutputEntry::file(name.clone(), concat(sources, size, mtime))])
+      },
+      Builtin::LineNumber => inputs.iter()
+        .filter_map(|i| Some((output_path(i), Source::new(i)?)))
+        .map(|(path, source)| Ok(OutputEntry::file(path, line_number(source)?)))
+        .collect(),
+      Builtin::Rename { regex, to } => map_paths(inputs, |path| {
+        let renamed = regex.replace(path.as_bytes(), to.as_bytes());
+        Some(OsString::from_vec(renamed.into_owned()))
+      }),
+      Builtin::Prefix { path: prefix } => map_paths(inputs, |path| Some(prefix.join(path).into_os_string()))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // source in memory returning at most `chunk` bytes per read
+  fn memory(data: &'static [u8], chunk: u32) -> Source {
+    let len = data.len() as u64;
+    Source::Stage(Arc::new(OutputFile::new(OutputFileMetadata::new(len), move |offset, size| {
+      let start = offset.min(len) as usize;
+      let end = offset.saturating_add(size.min(chunk) as u64).min(len) as usize;
+      Ok(data[start..end].to_vec())
+    })))
+  }
+
+  // check reads of all ranges (including past the end) against the expected content
+  fn check_reads(file: &OutputFile, expected: &[u8]) {
+    assert_eq!(file.size(), expected.len() as u64);
+    for offset in 0..=expected.len() + 1 {
+      for size in 0..=expected.len() + 1 {
+        let start = offset.min(expected.len());
+        let end = (offset + size).min(expected.len());
+        let data = (file.read)(offset as u64, size as u32).unwrap();
+        assert_eq!(data, &expected[start..end], "offset {} size {}", offset, size);
+      }
+    }
+  }
+
+  #[test]
+  fn split_words_quoting() {
+    assert_eq!(split_words("  rename --regex  x ").unwrap(), ["rename", "--regex", "x"]);
+    assert_eq!(split_words(r#"a "b c" 'd "e"'"#).unwrap(), ["a", "b c", r#"d "e""#]);
+    // quotes join with adjacent characters and may be empty
+    assert_eq!(split_words(r#"a"b c"d '' """#).unwrap(), ["ab cd", "", ""]);
+    assert!(split_words("a 'b").is_err());
+    assert!(split_words("").unwrap().is_empty());
+  }
+
+  #[test]
+  fn parse_builtin() {
+    let b: Builtin = "rename --regex '\\.md$' --to '.txt'".parse().unwrap();
+    assert!(matches!(b, Builtin::Rename { ref to, .. } if to == ".txt"));
+    assert!("unknown".parse::<Builtin>().is_err());
+  }
+
+  #[test]
+  fn concat_across_sources() {
+    let sources = [(&b"abc"[..], 3), (b"", 1), (b"de", 1), (b"fghi", 2)];
+    let mut offset = 0;
+    let sources = sources.into_iter()
+      .map(|(data, chunk)| {
+        let start = offset;
+        offset += data.len() as u64;
+        (start, memory(data, chunk))
+      })
+      .collect();
+    check_reads(&concat(sources, offset, None), b"abcdefghi");
+  }
+
+  #[test]
+  fn line_number_reads() {
+    let file = line_number(memory(b"a\nbb\n\nccc", 2)).unwrap();
+    check_reads(&file, b"1 a\n2 bb\n3 \n4 ccc");
+    let file = line_number(memory(b"", 2)).unwrap();
+    check_reads(&file, b"");
+  }
+
+  #[test]
+  fn output_paths() {
+    let input = |path: &str, root: Option<&str>| Input {
+      path: path.into(),
+      kind: InputKind::File,
+      root: root.map(PathBuf::from),
+      file: None
+    };
+    assert_eq!(output_path(&input("in/sub/a.md", Some("in"))), "sub/a.md");
+    assert_eq!(output_path(&input("/x/in/a.md", Some("/x/in/"))), "a.md");
+    assert_eq!(output_path(&input("in/a.md", Some("in/a.md"))), "a.md");
+    assert_eq!(output_path(&input("/a/../b", None)), "a/b");
+  }
+}
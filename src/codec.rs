@@ -0,0 +1,258 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use mlua::{Lua, LuaSerdeExt, String as LuaString, Table};
+
+type DecodeFn = fn(&Lua, &[u8]) -> anyhow::Result<mlua::Value>;
+type EncodeFn = fn(&serde_json::Value, bool) -> anyhow::Result<String>;
+
+fn json_decode(lua: &Lua, data: &[u8]) -> anyhow::Result<mlua::Value> {
+  let value: serde_json::Value = serde_json::from_slice(data)?;
+  Ok(lua.to_value(&value)?)
+}
+
+fn json_encode(value: &serde_json::Value, pretty: bool) -> anyhow::Result<String> {
+  Ok(if pretty { serde_json::to_string_pretty(value)? } else { serde_json::to_string(value)? })
+}
+
+// keys that can't be keys of Lua tables (null, NaN and collections) become YAML strings
+fn yaml_key(key: serde_yaml::Value) -> anyhow::Result<serde_yaml::Value> {
+  Ok(match key {
+    serde_yaml::Value::Bool(_) | serde_yaml::Value::String(_) => key,
+    serde_yaml::Value::Number(ref n) if !n.as_f64().is_some_and(f64::is_nan) => key,
+    serde_yaml::Value::Tagged(t) => yaml_key(t.value)?,
+    key => serde_yaml::Value::String(serde_yaml::to_string(&key)?.trim_end().to_string())
+  })
+}
+
+// drop tags and convert keys
+fn yaml_value(value: serde_yaml::Value) -> anyhow::Result<serde_yaml::Value> {
+  Ok(match value {
+    serde_yaml::Value::Sequence(s) => serde_yaml::Value::Sequence(
+      s.into_iter().map(yaml_value).collect::<anyhow::Result<_>>()?
+    ),
+    serde_yaml::Value::Mapping(m) => serde_yaml::Value::Mapping(
+      m.into_iter()
+        .map(|(k, v)| Ok((yaml_key(k)?, yaml_value(v)?)))
+        .collect::<anyhow::Result<_>>()?
+    ),
+    serde_yaml::Value::Tagged(t) => yaml_value(t.value)?,
+    v => v
+  })
+}
+
+fn yaml_decode(lua: &Lua, data: &[u8]) -> anyhow::Result<mlua::Value> {
+  let value: serde_yaml::Value = serde_yaml::from_slice(data)?;
+  Ok(lua.to_value(&yaml_value(value)?)?)
+}
+
+fn yaml_encode(value: &serde_json::Value, _pretty: bool) -> anyhow::Result<String> {
+  Ok(serde_yaml::to_string(value)?)
+}
+
+// datetimes become strings (RFC 3339)
+fn toml_value(value: toml::Value) -> toml::Value {
+  match value {
+    toml::Value::Datetime(d) => toml::Value::String(d.to_string()),
+    toml::Value::Array(a) => toml::Value::Array(a.into_iter().map(toml_value).collect()),
+    toml::Value::Table(t) => toml::Value::Table(t.into_iter().map(|(k, v)| (k, toml_value(v))).collect()),
+    v => v
+  }
+}
+
+fn toml_decode(lua: &Lua, data: &[u8]) -> anyhow::Result<mlua::Value> {
+  let value: toml::Value = toml::from_str(std::str::from_utf8(data)?)?;
+  Ok(lua.to_value(&toml_value(value))?)
+}
+
+fn toml_encode(value: &serde_json::Value, pretty: bool) -> anyhow::Result<String> {
+  Ok(if pretty { toml::to_string_pretty(value)? } else { toml::to_string(value)? })
+}
+
+// add a table with decode and encode converting through serde
+fn add_codec(lua: &Lua, lib: &Table, name: &'static str, decode: DecodeFn, encode: EncodeFn) -> mlua::Result<()> {
+  let codec = lua.create_table()?;
+  codec.set("decode", lua.create_function(move |lua, data: LuaString| {
+    decode(lua, &data.as_bytes()[..])
+      .map_err(|e| mlua::Error::runtime(format!("Failed to decode {}: {:#}", name, e)))
+  })?)?;
+  codec.set("encode", lua.create_function(move |lua, (value, options): (mlua::Value, Option<Table>)| {
+    let value: serde_json::Value = lua.from_value(value)?;
+    let pretty = match options {
+      Some(options) => options.get::<_, Option<bool>>("pretty")?.unwrap_or(false),
+      None => false
+    };
+    let data = encode(&value, pretty)
+      .map_err(|e| mlua::Error::runtime(format!("Failed to encode {}: {:#}", name, e)))?;
+    lua.create_string(data)
+  })?)?;
+  lib.set(name, codec)
+}
+
+fn csv_error(e: impl std::fmt::Display) -> mlua::Error {
+  mlua::Error::runtime(format!("CSV error: {}", e))
+}
+
+fn csv_delimiter(options: &Option<Table>) -> mlua::Result<u8> {
+  let Some(options) = options else {
+    return Ok(b',');
+  };
+  match options.get::<_, Option<LuaString>>("delimiter")? {
+    None => Ok(b','),
+    Some(d) if d.as_bytes().len() == 1 => Ok(d.as_bytes()[0]),
+    Some(_) => Err(mlua::Error::runtime("CSV delimiter must be a single byte"))
+  }
+}
+
+// field of a row to encode
+fn csv_field(value: mlua::Value) -> mlua::Result<Vec<u8>> {
+  Ok(match value {
+    mlua::Value::Nil => Vec::new(),
+    mlua::Value::String(s) => s.as_bytes().to_vec(),
+    mlua::Value::Integer(i) => i.to_string().into_bytes(),
+    mlua::Value::Number(n) => n.to_string().into_bytes(),
+    mlua::Value::Boolean(b) => b.to_string().into_bytes(),
+    v => return Err(mlua::Error::runtime(format!("Invalid CSV field of type {}", v.type_name())))
+  })
+}
+
+// rows are arrays of strings, or tables keyed by column names with the header option
+fn add_csv(lua: &Lua, lib: &Table) -> mlua::Result<()> {
+  let csv = lua.create_table()?;
+  csv.set("decode", lua.create_function(|lua, (data, options): (LuaString, Option<Table>)| {
+    let header = match &options {
+      Some(options) => options.get::<_, Option<bool>>("header")?.unwrap_or(false),
+      None => false
+    };
+    let data = data.as_bytes().to_vec();
+    let mut reader = csv::ReaderBuilder::new()
+      .has_headers(header)
+      .delimiter(csv_delimiter(&options)?)
+      .flexible(true)
+      .from_reader(&data[..]);
+    let columns = if header {
+      Some(reader.byte_headers().map_err(csv_error)?.clone())
+    } else {
+      None
+    };
+    let rows = lua.create_table()?;
+    for (n, record) in reader.byte_records().enumerate() {
+      let record = record.map_err(csv_error)?;
+      let row = lua.create_table()?;
+      for (i, field) in record.iter().enumerate() {
+        let value = lua.create_string(field)?;
+        match columns.as_ref().map(|c| c.get(i)) {
+          // extra fields without a column name are dropped
+          Some(Some(column)) => row.set(lua.create_string(column)?, value)?,
+          Some(None) => {},
+          None => row.set(i + 1, value)?
+        };
+      }
+      rows.set(n + 1, row)?;
+    }
+    Ok(rows)
+  })?)?;
+  csv.set("encode", lua.create_function(|lua, (rows, options): (Vec<Table>, Option<Table>)| {
+    let header = match &options {
+      Some(options) => options.get::<_, Option<Vec<LuaString>>>("header")?,
+      None => None
+    };
+    let mut writer = csv::WriterBuilder::new()
+      .delimiter(csv_delimiter(&options)?)
+      .flexible(true)
+      .from_writer(Vec::new());
+    if let Some(header) = &header {
+      writer.write_record(header.iter().map(|h| h.as_bytes().to_vec())).map_err(csv_error)?;
+    }
+    for row in rows {
+      let fields = match &header {
+        Some(header) => header.iter()
+          .map(|h| csv_field(row.get(h.clone())?))
+          .collect::<mlua::Result<Vec<_>>>()?,
+        None => row.sequence_values::<mlua::Value>()
+          .map(|v| csv_field(v?))
+          .collect::<mlua::Result<Vec<_>>>()?
+      };
+      writer.write_record(fields).map_err(csv_error)?;
+    }
+    lua.create_string(writer.into_inner().map_err(csv_error)?)
+  })?)?;
+  lib.set("csv", csv)
+}
+
+/// Add JSON, YAML, TOML and CSV codecs to the transformfs module
+pub fn register(lua: &Lua, lib: &Table) -> mlua::Result<()> {
+  add_codec(lua, lib, "json", json_decode, json_encode)?;
+  add_codec(lua, lib, "yaml", yaml_decode, yaml_encode)?;
+  add_codec(lua, lib, "toml", toml_decode, toml_encode)?;
+  add_csv(lua, lib)?;
+  // value of null in decoded data
+  lib.set("null", lua.null())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // run a chunk with the codecs in global `t`
+  fn eval<T: mlua::FromLuaMulti>(code: &str) -> T {
+    let lua = Lua::new();
+    let lib = lua.create_table().unwrap();
+    register(&lua, &lib).unwrap();
+    lua.globals().set("t", lib).unwrap();
+    lua.load(code).eval().unwrap()
+  }
+
+  #[test]
+  fn round_trips() {
+    for codec in ["json", "yaml", "toml"] {
+      let same: bool = eval(&format!(r#"
+        local c = t.{codec}
+        local data = {{ name = "a", n = 1, list = {{ 1, 2, 3 }}, nested = {{ ok = true }} }}
+        local v = c.decode(c.encode(c.decode(c.encode(data))))
+        return v.name == "a" and v.n == 1 and #v.list == 3 and v.list[3] == 3 and v.nested.ok == true
+      "#));
+      assert!(same, "{} round trip", codec);
+    }
+
+    let rows: String = eval(r#"
+      local data = t.csv.encode({ { a = "1", b = "x,y" }, { a = "2" } }, { header = { "a", "b" } })
+      local rows = t.csv.decode(data, { header = true })
+      return data .. "|" .. rows[1].b .. "|" .. rows[2].a .. "|" .. rows[2].b
+    "#);
+    assert_eq!(rows, "a,b\n1,\"x,y\"\n2,\n|x,y|2|");
+    let delimited: String = eval(r#"
+      local rows = t.csv.decode(t.csv.encode({ { "a", 1 } }, { delimiter = ";" }), { delimiter = ";" })
+      return rows[1][1] .. rows[1][2]
+    "#);
+    assert_eq!(delimited, "a1");
+  }
+
+  #[test]
+  fn decode_special_values() {
+    let toml: String = eval(r#"
+      local v = t.toml.decode("d = 1979-05-27T07:32:00Z\n[t]\nx = 1979-05-27")
+      return v.d .. " " .. v.t.x
+    "#);
+    assert_eq!(toml, "1979-05-27T07:32:00Z 1979-05-27");
+    let yaml: String = eval(r#"
+      local v = t.yaml.decode("1: a\ntrue: b\n~: c\n[x]: d\nt: !tag e\n")
+      return v[1] .. v[true] .. v["null"] .. v["- x"] .. v.t
+    "#);
+    assert_eq!(yaml, "abcde");
+    let null: bool = eval(r#"return t.json.decode("[null]")[1] == t.null"#);
+    assert!(null);
+  }
+}
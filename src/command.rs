@@ -0,0 +1,344 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{collections::HashMap, ffi::OsString, fs::{self, File, OpenOptions}, io::{self, Read}, os::unix::fs::FileExt, path::{Path, PathBuf}, process::{Child, Command, ExitStatus, Stdio}, sync::{atomic::{AtomicU64, Ordering}, Arc, LazyLock, Mutex, PoisonError, Weak}, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use log::{debug, info, warn};
+use crate::{output::{OutputFile, OutputFileMetadata}, transform::LimitExceeded};
+
+/// Interval to check whether a command has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Counter to name temporary buffer files
+static NEXT_BUFFER: AtomicU64 = AtomicU64::new(0);
+
+/// Captured stdout of a command
+enum Buffer {
+  Memory(Vec<u8>),
+  /// Unlinked temporary file
+  Disk { file: File, size: u64 }
+}
+
+impl Buffer {
+  fn size(&self) -> u64 {
+    match self {
+      Buffer::Memory(data) => data.len() as u64,
+      Buffer::Disk { size, .. } => *size
+    }
+  }
+
+  fn read(&self, offset: u64, size: u32) -> anyhow::Result<Vec<u8>> {
+    match self {
+      Buffer::Memory(data) => {
+        let start = (offset as usize).min(data.len());
+        let end = start.saturating_add(size as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+      },
+      Buffer::Disk { file, size: file_size } => {
+        let len = file_size.saturating_sub(offset).min(size as u64) as usize;
+        let mut data = vec![0; len];
+        file.read_exact_at(&mut data, offset)?;
+        Ok(data)
+      }
+    }
+  }
+}
+
+// temporary file removed right after creation (kept until closed)
+fn temp_file() -> anyhow::Result<File> {
+  let path = std::env::temp_dir().join(format!(
+    "transformfs-{}-{}",
+    std::process::id(),
+    NEXT_BUFFER.fetch_add(1, Ordering::Relaxed)
+  ));
+  let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)
+    .map_err(|e| anyhow::anyhow!("Failed to create buffer file {:?}: {}", path, e))?;
+  fs::remove_file(&path)?;
+  Ok(file)
+}
+
+// read a pipe in background so that the command doesn't block on a full pipe
+fn read_pipe(mut pipe: impl Read + Send + 'static) -> JoinHandle<io::Result<Vec<u8>>> {
+  thread::spawn(move || {
+    let mut data = Vec::new();
+    pipe.read_to_end(&mut data)?;
+    Ok(data)
+  })
+}
+
+// wait for the child until the timeout (None if it's still running)
+fn wait_timeout(child: &mut Child, timeout: Option<Duration>) -> io::Result<Option<ExitStatus>> {
+  let Some(timeout) = timeout else {
+    return child.wait().map(Some);
+  };
+  let deadline = Instant::now() + timeout;
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(Some(status));
+    }
+    let now = Instant::now();
+    if now >= deadline {
+      return Ok(None);
+    }
+    thread::sleep(POLL_INTERVAL.min(deadline - now));
+  }
+}
+
+fn join_pipe(handle: Option<JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+  match handle {
+    Some(h) => h.join().unwrap_or_else(|_| Err(io::Error::other("pipe reader panicked"))),
+    None => Ok(Vec::new())
+  }
+}
+
+/// Output of a command run on first open
+pub struct CommandOutput {
+  args: Vec<OsString>,
+  /// Buffer stdout in a temporary file instead of memory
+  disk: bool,
+  /// Kill the command if it runs longer than this
+  timeout: Option<Duration>,
+  /// Stdout of the last successful run (failed runs are retried on next open)
+  buffer: Mutex<Option<Arc<Buffer>>>
+}
+
+impl CommandOutput {
+  pub fn new(args: Vec<OsString>, disk: bool, timeout: Option<Duration>) -> Self {
+    Self {
+      args,
+      disk,
+      timeout,
+      buffer: Mutex::new(None)
+    }
+  }
+
+  fn run(&self) -> anyhow::Result<Buffer> {
+    let Some((program, args)) = self.args.split_first() else {
+      anyhow::bail!("Command is empty");
+    };
+    info!("Run command {:?}", self.args);
+    let mut command = Command::new(program);
+    command.args(args).stdin(Stdio::null()).stderr(Stdio::piped());
+    let file = if self.disk {
+      let file = temp_file()?;
+      command.stdout(file.try_clone()?);
+      Some(file)
+    } else {
+      command.stdout(Stdio::piped());
+      None
+    };
+    let mut child = command.spawn()
+      .map_err(|e| anyhow::anyhow!("Failed to run command {:?}: {}", self.args, e))?;
+    let stdout = child.stdout.take().map(read_pipe);
+    let stderr = child.stderr.take().map(read_pipe);
+    let Some(status) = wait_timeout(&mut child, self.timeout)? else {
+      // readers of the pipes are left to finish on their own
+      if let Err(err) = child.kill().and_then(|_| child.wait()) {
+        warn!("Failed to kill command {:?}: {}", self.args, err);
+      }
+      let limit = self.timeout.unwrap_or_default();
+      return Err(anyhow::Error::new(LimitExceeded::Time(limit)).context(format!("Command {:?} timed out", self.args)));
+    };
+    let stdout = join_pipe(stdout)?;
+    let stderr = join_pipe(stderr)?;
+    let stderr = String::from_utf8_lossy(&stderr);
+    if !status.success() {
+      anyhow::bail!("Command {:?} failed ({}): {}", self.args, status, stderr.trim());
+    }
+    if !stderr.is_empty() {
+      debug!("Stderr of command {:?}: {}", self.args, stderr.trim());
+    }
+    Ok(match file {
+      Some(file) => {
+        let size = file.metadata()?.len();
+        Buffer::Disk { file, size }
+      },
+      None => Buffer::Memory(stdout)
+    })
+  }
+
+  // run the command if it hasn't succeeded yet
+  fn buffer(&self) -> anyhow::Result<Arc<Buffer>> {
+    let mut buffer = self.buffer.lock().unwrap();
+    if let Some(b) = &*buffer {
+      return Ok(b.clone());
+    }
+    let b = Arc::new(self.run()?);
+    *buffer = Some(b.clone());
+    Ok(b)
+  }
+
+  /// Size of stdout (None before the command succeeds)
+  pub fn size(&self) -> Option<u64> {
+    self.buffer.lock().unwrap().as_ref().map(|b| b.size())
+  }
+
+  /// Output file reading stdout of the command
+  pub fn file(self: Arc<Self>, mtime: Option<u64>) -> OutputFile {
+    let o = self.clone();
+    OutputFile::new(OutputFileMetadata { mtime, ..OutputFileMetadata::new(0) }, move |offset, size| {
+      o.buffer()?.read(offset, size)
+    })
+    .on_open({
+      let o = self.clone();
+      move || o.buffer().map(|_| ())
+    })
+    .with_dynamic_size(move || self.size())
+  }
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+  fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Command with the options of its output
+#[derive(PartialEq, Eq, Hash)]
+struct CacheKey {
+  args: Vec<OsString>,
+  disk: bool,
+  timeout: Option<Duration>
+}
+
+/// Cached command and the mtimes of its inputs when created
+struct CacheEntry {
+  mtimes: Vec<Option<SystemTime>>,
+  output: Weak<CommandOutput>
+}
+
+/// Cached commands of all transforms in this process
+static CACHE_ENTRIES: LazyLock<Mutex<HashMap<CacheKey, CacheEntry>>> = LazyLock::new(Mutex::default);
+
+/// Command outputs reused across transforms until their inputs change
+///
+/// Entries are shared by all transforms (and mounts) in the process
+/// and dropped with the outputs using them
+#[derive(Clone, Default)]
+pub struct CommandCache {
+  /// Timeout of each command
+  timeout: Option<Duration>
+}
+
+impl CommandCache {
+  pub fn new(timeout: Option<Duration>) -> Self {
+    Self { timeout }
+  }
+
+  /// Output file of a command whose result depends on the inputs
+  /// (arguments that are existing files if None)
+  pub fn file(&self, args: Vec<OsString>, inputs: Option<Vec<PathBuf>>, disk: bool) -> OutputFile {
+    let inputs = inputs.unwrap_or_else(|| {
+      args.iter().skip(1).map(PathBuf::from).filter(|p| p.is_file()).collect()
+    });
+    let mtimes: Vec<_> = inputs.iter().map(|p| mtime(p)).collect();
+    let latest = mtimes.iter()
+      .flatten()
+      .max()
+      .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+      .map(|d| d.as_secs());
+
+    let key = CacheKey { args, disk, timeout: self.timeout };
+    let mut cache = CACHE_ENTRIES.lock().unwrap_or_else(PoisonError::into_inner);
+    cache.retain(|_, e| e.output.strong_count() > 0);
+    let cached = cache.get(&key)
+      .filter(|e| e.mtimes == mtimes)
+      .and_then(|e| e.output.upgrade());
+    let output = match cached {
+      Some(output) => output,
+      None => {
+        if cache.contains_key(&key) {
+          info!("Inputs of command {:?} changed, rerun on next open", key.args);
+        }
+        let output = Arc::new(CommandOutput::new(key.args.clone(), disk, self.timeout));
+        cache.insert(key, CacheEntry {
+          mtimes,
+          output: Arc::downgrade(&output)
+        });
+        output
+      }
+    };
+    output.file(latest)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+  }
+
+  fn open(file: &OutputFile) -> anyhow::Result<()> {
+    (file.open.as_ref().unwrap())()
+  }
+
+  #[test]
+  fn run_on_open() {
+    let cache = CommandCache::default();
+    for disk in [false, true] {
+      let file = cache.file(args(&["echo", "run_on_open"]), None, disk);
+      assert_eq!(file.size(), 0);
+      open(&file).unwrap();
+      assert_eq!(file.size(), 12);
+      assert_eq!((file.read)(4, 100).unwrap(), b"on_open\n");
+      // shared while alive
+      assert_eq!(cache.file(args(&["echo", "run_on_open"]), None, disk).size(), 12);
+    }
+  }
+
+  #[test]
+  fn rerun_on_failure() {
+    let dir = std::env::temp_dir().join(format!("transformfs-rerun-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let mkdir = CommandCache::default().file(args(&["mkdir", dir.to_str().unwrap()]), Some(Vec::new()), false);
+    let ls = CommandCache::default().file(args(&["ls", dir.to_str().unwrap()]), Some(Vec::new()), false);
+    // fails until the dir exists
+    assert!(open(&ls).is_err());
+    open(&mkdir).unwrap();
+    open(&ls).unwrap();
+    assert_eq!(ls.size(), 0);
+    fs::remove_dir(&dir).unwrap();
+  }
+
+  #[test]
+  fn rerun_on_input_change() {
+    let path = std::env::temp_dir().join(format!("transformfs-input-{}", std::process::id()));
+    fs::write(&path, "a").unwrap();
+    let cache = CommandCache::default();
+    let cat = || cache.file(args(&["cat", path.to_str().unwrap()]), None, false);
+    let first = cat();
+    open(&first).unwrap();
+    assert_eq!(cat().size(), 1);
+
+    fs::write(&path, "bb").unwrap();
+    File::options().write(true).open(&path).unwrap()
+      .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+    let second = cat();
+    assert_eq!(second.size(), 0);
+    open(&second).unwrap();
+    assert_eq!(second.size(), 2);
+    // old output keeps its content
+    assert_eq!((first.read)(0, 10).unwrap(), b"a");
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn kill_on_timeout() {
+    let file = CommandCache::new(Some(Duration::from_millis(100))).file(args(&["sleep", "5"]), None, false);
+    let start = Instant::now();
+    let err = open(&file).unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(matches!(err.downcast_ref::<LimitExceeded>(), Some(LimitExceeded::Time(_))));
+  }
+}
@@ -0,0 +1,312 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{collections::BTreeSet, ffi::OsString, fs::{self, File, Permissions}, io::{self, Read, Write}, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use log::{debug, warn};
+use crate::{output::OutputContent, transformfs::TransformFs};
+
+/// Size of each read call when exporting
+const CHUNK_SIZE: u32 = 1 << 20;
+
+/// File in the destination dir listing the paths written by the last export
+/// (only these are removed when they are no longer outputs)
+const MANIFEST_FILE: &str = ".transformfs-export";
+
+/// Entry to export (path relative to the root of output)
+enum ExportEntry {
+  Dir(PathBuf),
+  File {
+    path: PathBuf,
+    ino: u64,
+    size: u64,
+    mtime: Option<u64>
+  }
+}
+
+impl ExportEntry {
+  fn path(&self) -> &Path {
+    match self {
+      ExportEntry::Dir(path) | ExportEntry::File { path, .. } => path
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct ExportStats {
+  pub written: usize,
+  pub skipped: usize,
+  /// Files of the last export that are no longer outputs
+  pub removed: usize
+}
+
+// entries sorted by path so that parent dirs come first
+fn entries(fs: &TransformFs) -> Vec<ExportEntry> {
+  let output = fs.output();
+  let mut paths: Vec<(&OsString, &u64)> = output.path_map.iter().collect();
+  paths.sort();
+  paths.into_iter()
+    .filter_map(|(path, ino)| {
+      let path = Path::new(path).strip_prefix("/").ok()?.to_path_buf();
+      if path.as_os_str().is_empty() {
+        return None;
+      }
+      match &output.inode_map.get(ino)?.content {
+        OutputContent::Dir(_) => Some(ExportEntry::Dir(path)),
+        OutputContent::File(f) => Some(ExportEntry::File {
+          path,
+          ino: *ino,
+          size: f.size(),
+          mtime: f.metadata.mtime
+        }),
+        OutputContent::Control(_) => None
+      }
+    })
+    .collect()
+}
+
+fn to_system_time(mtime: u64) -> SystemTime {
+  UNIX_EPOCH + Duration::from_secs(mtime)
+}
+
+// an existing file can be skipped only if the output has mtime
+fn is_unchanged(dest: &Path, size: u64, mtime: Option<u64>) -> bool {
+  let (Some(mtime), Ok(metadata)) = (mtime, fs::symlink_metadata(dest)) else {
+    return false;
+  };
+  metadata.is_file()
+    && metadata.len() == size
+    && metadata.modified().is_ok_and(|t| t == to_system_time(mtime))
+}
+
+// read the whole file (padded with zeros or truncated to size)
+// so that size in metadata (after opening) is always respected
+fn copy_file(fs: &mut TransformFs, path: &Path, ino: u64, w: &mut impl Write) -> anyhow::Result<()> {
+  let mut reader = fs.reader(ino, 0, u64::MAX, CHUNK_SIZE)
+    .map_err(|errno| anyhow::anyhow!("Failed to open {:?}: {}", path, errno))?;
+  let size = reader.size();
+  let copied = io::copy(&mut reader.by_ref().take(size), w)?;
+  if copied < size {
+    warn!("Output {:?} is shorter than its size ({} < {} bytes), padded with zeros", path, copied, size);
+    io::copy(&mut io::repeat(0).take(size - copied), w)?;
+  }
+  reader.close()
+    .map_err(|errno| anyhow::anyhow!("Failed to close {:?}: {}", path, errno))?;
+  Ok(())
+}
+
+// paths written by the last export (empty if not exported before)
+fn read_manifest(dest: &Path) -> anyhow::Result<BTreeSet<PathBuf>> {
+  let path = dest.join(MANIFEST_FILE);
+  match fs::read(&path) {
+    Ok(content) => serde_json::from_slice(&content)
+      .map_err(|e| anyhow::anyhow!("Invalid export manifest {:?}: {}", path, e)),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+    Err(err) => anyhow::bail!("Failed to read {:?}: {}", path, err)
+  }
+}
+
+fn write_manifest(dest: &Path, paths: &BTreeSet<PathBuf>) -> anyhow::Result<()> {
+  let path = dest.join(MANIFEST_FILE);
+  let tmp = temp_path(&path);
+  fs::write(&tmp, serde_json::to_vec(paths)?)?;
+  fs::rename(&tmp, &path)?;
+  Ok(())
+}
+
+// hidden file next to the destination (so that rename is atomic)
+fn temp_path(path: &Path) -> PathBuf {
+  let mut name = OsString::from(".");
+  name.push(path.file_name().unwrap_or_default());
+  name.push(".transformfs-tmp");
+  path.with_file_name(name)
+}
+
+// remove paths of the last export that are no longer outputs
+// (children first so that dirs left empty can be removed)
+fn remove_stale(dest: &Path, prev: &BTreeSet<PathBuf>, current: &BTreeSet<PathBuf>) -> anyhow::Result<usize> {
+  let mut removed = 0;
+  for path in prev.iter().rev().filter(|p| !current.contains(*p)) {
+    let dest_path = dest.join(path);
+    let result = match fs::symlink_metadata(&dest_path) {
+      Ok(m) if m.is_dir() => match fs::remove_dir(&dest_path) {
+        // files not written by export are kept
+        Err(err) if err.kind() == io::ErrorKind::DirectoryNotEmpty => {
+          debug!("Keep non-empty dir {:?}", dest_path);
+          continue;
+        },
+        r => r
+      },
+      Ok(_) => fs::remove_file(&dest_path).map(|_| removed += 1),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+      Err(err) => Err(err)
+    };
+    result.map_err(|e| anyhow::anyhow!("Failed to remove {:?}: {}", dest_path, e))?;
+  }
+  Ok(removed)
+}
+
+// create a dir without following an existing symlink
+fn create_dir(dest_path: &Path) -> anyhow::Result<()> {
+  match fs::symlink_metadata(dest_path) {
+    Ok(m) if m.is_dir() => Ok(()),
+    Ok(_) => anyhow::bail!("Failed to create dir {:?}: a file exists at this path", dest_path),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => fs::create_dir(dest_path)
+      .map_err(|e| anyhow::anyhow!("Failed to create dir {:?}: {}", dest_path, e)),
+    Err(err) => Err(err.into())
+  }
+}
+
+// write to a new temporary file and rename it into place
+// so that a symlink at the destination is replaced instead of followed
+fn write_file(fs: &mut TransformFs, path: &Path, dest_path: &Path, ino: u64, mtime: Option<u64>) -> anyhow::Result<()> {
+  let tmp = temp_path(dest_path);
+  match fs::remove_file(&tmp) {
+    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+    _ => ()
+  };
+  let mut file = File::options().write(true).create_new(true).open(&tmp)
+    .map_err(|e| anyhow::anyhow!("Failed to create {:?}: {}", tmp, e))?;
+  let result = copy_file(fs, path, ino, &mut file)
+    .and_then(|_| {
+      file.set_permissions(Permissions::from_mode(0o644))?;
+      if let Some(mtime) = mtime {
+        file.set_modified(to_system_time(mtime))?;
+      }
+      fs::rename(&tmp, dest_path)
+        .map_err(|e| anyhow::anyhow!("Failed to replace {:?}: {}", dest_path, e))
+    });
+  if result.is_err() {
+    let _ = fs::remove_file(&tmp);
+  }
+  result
+}
+
+/// Write outputs into a dir, skipping files whose size and mtime already match
+///
+/// Files written by the last export that are no longer outputs are removed (other files are kept)
+pub fn export_dir(fs: &mut TransformFs, dest: &Path) -> anyhow::Result<ExportStats> {
+  let mut stats = ExportStats::default();
+  fs::create_dir_all(dest)?;
+  let entries = entries(fs);
+  let paths: BTreeSet<_> = entries.iter().map(|e| e.path().to_path_buf()).collect();
+  stats.removed = remove_stale(dest, &read_manifest(dest)?, &paths)?;
+
+  for entry in entries {
+    match entry {
+      ExportEntry::Dir(path) => {
+        create_dir(&dest.join(path))?;
+      },
+      ExportEntry::File { path, ino, size, mtime } => {
+        let dest_path = dest.join(&path);
+        if is_unchanged(&dest_path, size, mtime) {
+          debug!("Skip unchanged file {:?}", dest_path);
+          stats.skipped += 1;
+          continue;
+        }
+        write_file(fs, &path, &dest_path, ino, mtime)?;
+        stats.written += 1;
+      }
+    };
+  }
+  write_manifest(dest, &paths)?;
+  Ok(stats)
+}
+
+/// Write outputs as a tar archive
+pub fn export_tar(fs: &mut TransformFs, w: impl Write) -> anyhow::Result<ExportStats> {
+  let mut stats = ExportStats::default();
+  let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let mut builder = tar::Builder::new(w);
+  for entry in entries(fs) {
+    let mut header = tar::Header::new_gnu();
+    match entry {
+      ExportEntry::Dir(path) => {
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(now);
+        header.set_size(0);
+        builder.append_data(&mut header, path, io::empty())?;
+      },
+      ExportEntry::File { path, ino, mtime, .. } => {
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_mtime(mtime.unwrap_or(now));
+        let mut reader = fs.reader(ino, 0, u64::MAX, CHUNK_SIZE)
+          .map_err(|errno| anyhow::anyhow!("Failed to open {:?}: {}", path, errno))?;
+        let size = reader.size();
+        header.set_size(size);
+        // tar entries must match the size in header
+        let data = (&mut reader).take(size).chain(io::repeat(0)).take(size);
+        builder.append_data(&mut header, &path, data)?;
+        reader.close()
+          .map_err(|errno| anyhow::anyhow!("Failed to close {:?}: {}", path, errno))?;
+        stats.written += 1;
+      }
+    };
+  }
+  builder.into_inner()?.flush()?;
+  Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{output::{OutputEntry, OutputFile, OutputFileMetadata}, transform::Transform, utils::Input};
+
+  /// Transform with fixed files
+  struct Files(Vec<(&'static str, &'static str)>);
+
+  impl Transform for Files {
+    fn transform(&self, _inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+      Ok(self.0.iter().map(|(path, content)| {
+        let data = content.as_bytes().to_vec();
+        OutputEntry::file(*path, OutputFile::new(OutputFileMetadata::new(data.len() as u64), move |offset, size| {
+          Ok(data.iter().skip(offset as usize).take(size as usize).copied().collect())
+        }))
+      }).collect())
+    }
+  }
+
+  fn export(dest: &Path, files: Vec<(&'static str, &'static str)>) -> ExportStats {
+    let mut fs = TransformFs::builder().transform(Files(files)).build().unwrap();
+    export_dir(&mut fs, dest).unwrap()
+  }
+
+  #[test]
+  fn export_to_dir() {
+    let dir = std::env::temp_dir().join(format!("transformfs-export-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let dest = dir.join("dest");
+    fs::create_dir_all(&dest).unwrap();
+    fs::write(dir.join("target"), "outside").unwrap();
+    std::os::unix::fs::symlink(dir.join("target"), dest.join("a")).unwrap();
+    fs::write(dest.join("mine"), "kept").unwrap();
+
+    let stats = export(&dest, vec![("a", "new"), ("b/c", "c"), ("b/d", "d")]);
+    assert_eq!((stats.written, stats.removed), (3, 0));
+    // symlink is replaced instead of followed
+    assert_eq!(fs::read_to_string(dir.join("target")).unwrap(), "outside");
+    assert!(fs::symlink_metadata(dest.join("a")).unwrap().is_file());
+    assert_eq!(fs::read_to_string(dest.join("a")).unwrap(), "new");
+
+    let stats = export(&dest, vec![("a", "new")]);
+    assert_eq!((stats.written, stats.removed), (1, 2));
+    assert!(!dest.join("b").exists());
+    assert_eq!(fs::read_to_string(dest.join("mine")).unwrap(), "kept");
+    let names: BTreeSet<_> = fs::read_dir(&dest).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(names, ["a", "mine", MANIFEST_FILE].into_iter().map(OsString::from).collect());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,68 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A FUSE filesystem to transform input files to output files with Lua script
+//!
+//! Use [`TransformFs::builder`] to embed it:
+//!
+//! ```no_run
+//! use transformfs::{Config, TransformFs};
+//!
+//! let handle = TransformFs::builder()
+//!   .script("transform.lua")
+//!   .inputs(["data/"])
+//!   .config(Config::default())
+//!   .spawn_mount("/mnt/transformed")?;
+//! // ...
+//! handle.unmount();
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#[cfg(not(any(feature = "luajit", feature = "lua54", feature = "luau")))]
+compile_error!("One Lua runtime feature is required: luajit, lua54 or luau");
+
+#[cfg(any(
+  all(feature = "luajit", feature = "lua54"),
+  all(feature = "luajit", feature = "luau"),
+  all(feature = "lua54", feature = "luau")
+))]
+compile_error!("Only one Lua runtime feature can be enabled (use --no-default-features to disable luajit)");
+
+mod transformfs;
+mod transform;
+mod builtin;
+mod lua;
+mod codec;
+mod pipeline;
+mod sandbox;
+mod utils;
+mod output;
+mod command;
+mod watch;
+mod export;
+mod builder;
+mod mounts;
+mod worker;
+
+pub use transformfs::{Config, OnError, Params, SharedFs, TransformFs};
+pub use builtin::Builtin;
+pub use transform::{LimitExceeded, Transform, TransformLoader};
+pub use lua::{LuaTransform, ScriptOptions};
+pub use builder::{MountHandle, TransformFsBuilder};
+pub use output::{Output, OutputContent, OutputEntry, OutputFile, OutputFileMetadata, OutputPatch};
+pub use utils::{Input, InputKind};
+pub use export::{export_dir, export_tar, ExportStats};
+pub use mounts::{load_mounts, serve_mounts, unmount_mounts, MountConfig};
+pub use worker::{serve as serve_worker, WORKER_ARG};
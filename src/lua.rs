@@ -0,0 +1,568 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{ffi::OsString, fs, io::{Read, Seek, SeekFrom}, os::unix::ffi::{OsStrExt, OsStringExt}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use mlua::{FromLua, FromLuaMulti, Function, IntoLuaMulti, Lua, LuaSerdeExt, String as LuaString, Table};
+use crate::{codec, command::CommandCache, output::{OutputContent, OutputEntry, OutputFile, OutputFileMetadata, OutputPatch}, transform::{LimitExceeded, Transform, TransformLoader}, sandbox::Sandbox, transformfs::Params, utils::Input};
+
+/// Instructions run between checks of the time limit
+#[cfg(not(feature = "luau"))]
+const HOOK_INSTRUCTIONS: u32 = 10000;
+
+fn load_fn(table: &Table, name: &str) -> mlua::Result<Option<Function>> {
+  Ok(
+    if table.contains_key(name)? {
+      Some(table.get::<_, Function>(name)?)
+    } else {
+      None
+    }
+  )
+}
+
+struct UserFn {
+  setup: Option<Function>,
+  transform: Function,
+  on_change: Option<Function>
+}
+
+impl FromLua for UserFn {
+  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+    let mlua::Value::Table(table) = &value else {
+      return Err(mlua::Error::runtime("User script must export a Lua table"));
+    };
+    Ok(UserFn {
+      setup: load_fn(table, "setup")?,
+      transform: load_fn(table, "transform")?.ok_or(
+        mlua::Error::runtime("transform not defined in user module")
+      )?,
+      on_change: load_fn(table, "on_change")?
+    })
+  }
+}
+
+// whether a Lua error is caused by the memory limit
+fn is_memory_error(err: &mlua::Error) -> bool {
+  match err {
+    mlua::Error::MemoryError(_) => true,
+    mlua::Error::CallbackError { cause, .. } => is_memory_error(cause),
+    _ => false
+  }
+}
+
+/// Time limit of each call into a Lua state
+/// (stored in app data so callbacks returned by scripts can use it)
+#[derive(Clone, Default)]
+struct CallLimit {
+  limit: Option<Duration>,
+  /// Deadline of the running call
+  deadline: Arc<Mutex<Option<Instant>>>,
+  /// Set by the hook when it aborts the running call
+  expired: Arc<AtomicBool>
+}
+
+impl CallLimit {
+  // check the deadline periodically while Lua code is running
+  fn install(&self, lua: &Lua) -> mlua::Result<()> {
+    if self.limit.is_none() {
+      return Ok(());
+    }
+    let limit = self.clone();
+    #[cfg(not(feature = "luau"))]
+    lua.set_hook(
+      mlua::HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+      move |_, _| limit.check()
+    );
+    #[cfg(feature = "luau")]
+    lua.set_interrupt(move |_| limit.check().map(|_| mlua::VmState::Continue));
+    // hooks are not called in JIT-compiled code
+    #[cfg(feature = "luajit")]
+    lua.load("jit.off()").exec()?;
+    Ok(())
+  }
+
+  fn check(&self) -> mlua::Result<()> {
+    match *self.deadline.lock().unwrap() {
+      Some(deadline) if Instant::now() > deadline => {
+        self.expired.store(true, Ordering::SeqCst);
+        Err(mlua::Error::runtime("time limit exceeded"))
+      },
+      _ => Ok(())
+    }
+  }
+
+  /// Run a call into Lua with the time limit
+  /// (nested calls share the deadline of the outermost one)
+  fn run<R>(&self, f: impl FnOnce() -> mlua::Result<R>) -> anyhow::Result<R> {
+    let outermost = match self.limit {
+      Some(limit) => {
+        let mut deadline = self.deadline.lock().unwrap();
+        let outermost = deadline.is_none();
+        if outermost {
+          *deadline = Some(Instant::now() + limit);
+          self.expired.store(false, Ordering::SeqCst);
+        }
+        outermost
+      },
+      None => false
+    };
+    let result = f();
+    if outermost {
+      *self.deadline.lock().unwrap() = None;
+    }
+    result.map_err(|err| {
+      match self.limit {
+        Some(limit) if self.expired.load(Ordering::SeqCst) => LimitExceeded::Time(limit).into(),
+        _ if is_memory_error(&err) => LimitExceeded::Memory.into(),
+        _ => err.into()
+      }
+    })
+  }
+}
+
+/// Lua function with the state it is created in
+/// (Lua values only hold weak references to their state)
+struct LuaFn {
+  _lua: Lua,
+  function: Function,
+  limit: CallLimit
+}
+
+impl LuaFn {
+  fn new(lua: &Lua, function: Function) -> Self {
+    Self {
+      _lua: lua.clone(),
+      function,
+      limit: lua.app_data_ref::<CallLimit>().map(|l| l.clone()).unwrap_or_default()
+    }
+  }
+
+  fn call<A: IntoLuaMulti, R: FromLuaMulti>(&self, args: A) -> anyhow::Result<R> {
+    self.limit.run(|| self.function.call(args))
+  }
+}
+
+impl FromLua for OutputFileMetadata {
+  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+    let mlua::Value::Table(table) = &value else {
+      return Err(mlua::Error::runtime("OutputFileMetadata must be a Lua table"));
+    };
+    Ok(OutputFileMetadata {
+      size: table.get("size")?,
+      block_size: table.get("block_size")?,
+      mtime: table.get("mtime")?
+    })
+  }
+}
+
+impl FromLua for OutputEntry {
+  fn from_lua(value: mlua::Value, lua: &Lua) -> mlua::Result<Self> {
+    let mlua::Value::Table(table) = &value else {
+      return Err(mlua::Error::runtime("File must be a Lua table"));
+    };
+    let path = OsString::from_vec(
+      table.get::<_, LuaString>("path")?.as_bytes().to_vec()
+    );
+    // normalize path
+    let path = Path::new(&path).as_os_str().to_os_string();
+
+    if let Some(command) = table.get::<_, Option<Vec<LuaString>>>("command")? {
+      // commands are run by the cache only available without sandbox
+      let Some(cache) = lua.app_data_ref::<CommandCache>().map(|c| c.clone()) else {
+        return Err(mlua::Error::runtime("Command outputs are not allowed in sandbox mode"));
+      };
+      let to_os = |s: LuaString| OsString::from_vec(s.as_bytes().to_vec());
+      let inputs = table.get::<_, Option<Vec<LuaString>>>("inputs")?
+        .map(|inputs| inputs.into_iter().map(|s| PathBuf::from(to_os(s))).collect());
+      let disk = match table.get::<_, Option<String>>("buffer")?.as_deref() {
+        None | Some("memory") => false,
+        Some("disk") => true,
+        Some(b) => return Err(mlua::Error::runtime(format!("Invalid buffer {:?} (memory or disk)", b)))
+      };
+      let file = cache.file(command.into_iter().map(to_os).collect(), inputs, disk);
+      return Ok(OutputEntry::file(path, file));
+    }
+
+    let read = LuaFn::new(lua, table.get("read")?);
+    let mut file = OutputFile::new(table.get("metadata")?, move |offset, size| {
+      // HACK: as_bytes not available yet
+      Ok(read.call::<_, LuaString>((offset, size))?.as_bytes().to_vec())
+    });
+    if let Some(open) = table.get::<_, Option<Function>>("open")? {
+      let open = LuaFn::new(lua, open);
+      file = file.on_open(move || open.call::<_, ()>(()));
+    }
+    if let Some(close) = table.get::<_, Option<Function>>("close")? {
+      let close = LuaFn::new(lua, close);
+      file = file.on_close(move || close.call::<_, ()>(()));
+    }
+    Ok(OutputEntry {
+      path,
+      content: OutputContent::File(Arc::new(file))
+    })
+  }
+}
+
+impl FromLua for OutputPatch {
+  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+    let mlua::Value::Table(table) = &value else {
+      return Err(mlua::Error::runtime("Output patch must be a Lua table"));
+    };
+    let remove: Option<Vec<LuaString>> = table.get("remove")?;
+    Ok(OutputPatch {
+      add: table.get::<_, Option<Vec<OutputEntry>>>("add")?.unwrap_or_default(),
+      remove: remove.unwrap_or_default()
+        .into_iter()
+        .map(|p| OsString::from_vec(p.as_bytes().to_vec()))
+        .collect()
+    })
+  }
+}
+
+// expose output of the previous stage as a table with its callbacks
+fn stage_file_to_lua(lua: &Lua, path: LuaString, file: &Arc<OutputFile>) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  t.set("path", path)?;
+  t.set("type", "file")?;
+  if file.dynamic_size.is_some() {
+    // size is only known after opening (e.g. commands run on first open)
+    // so open the file when size is first used
+    let f = file.clone();
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.create_function(move |_, (_, key): (Table, LuaString)| {
+      if key.as_bytes() != b"size" {
+        return Ok(None);
+      }
+      if f.dynamic_size.as_ref().and_then(|size| size()).is_none() {
+        f.open.as_ref().map_or(Ok(()), |open| open()).map_err(mlua::Error::external)?;
+        f.close.as_ref().map_or(Ok(()), |close| close()).map_err(mlua::Error::external)?;
+      }
+      Ok(Some(f.size()))
+    })?)?;
+    t.set_metatable(Some(meta));
+  } else {
+    t.set("size", file.size())?;
+  }
+  let f = file.clone();
+  t.set("read", lua.create_function(move |lua, (offset, size): (u64, u32)| {
+    let data = (f.read)(offset, size).map_err(mlua::Error::external)?;
+    lua.create_string(data)
+  })?)?;
+  let f = file.clone();
+  t.set("open", lua.create_function(move |_, ()| {
+    f.open.as_ref().map_or(Ok(()), |open| open()).map_err(mlua::Error::external)
+  })?)?;
+  let f = file.clone();
+  t.set("close", lua.create_function(move |_, ()| {
+    f.close.as_ref().map_or(Ok(()), |close| close()).map_err(mlua::Error::external)
+  })?)?;
+  Ok(t)
+}
+
+// convert inputs to values passed to Lua
+fn inputs_to_lua(lua: &Lua, inputs: &[Input], all_entries: bool) -> mlua::Result<Table> {
+  lua.create_sequence_from(
+    inputs.iter()
+      .map(|i| {
+        let path = lua.create_string(i.path.as_bytes())?;
+        if let Some(file) = &i.file {
+          Ok(mlua::Value::Table(stage_file_to_lua(lua, path, file)?))
+        } else if all_entries {
+          // Pass every entry as a table with its path and type
+          let t = lua.create_table()?;
+          t.set("path", path)?;
+          t.set("type", i.kind.as_str())?;
+          Ok(mlua::Value::Table(t))
+        } else {
+          Ok(mlua::Value::String(path))
+        }
+      })
+      .collect::<mlua::Result<Vec<_>>>()?
+  )
+}
+
+/// Options to load a script
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ScriptOptions {
+  /// Pass inputs as tables with path and type
+  pub all_entries: bool,
+  /// Parameters passed to setup and transform
+  pub params: Params,
+  /// Extra dirs to search for modules
+  pub lua_path: Vec<PathBuf>,
+  /// Restrict access of the script (None for full access)
+  pub sandbox: Option<Sandbox>,
+  /// Time limit of each call into the script
+  pub time_limit: Option<Duration>,
+  /// Maximum heap size of the Lua state in bytes
+  pub memory_limit: Option<usize>,
+  /// Timeout of commands of outputs
+  pub command_timeout: Option<Duration>
+}
+
+/// Patterns of Lua modules under a search dir
+#[cfg(not(feature = "luau"))]
+pub(crate) const MODULE_PATTERNS: &[&str] = &["?.lua", "?/init.lua"];
+#[cfg(feature = "luau")]
+pub(crate) const MODULE_PATTERNS: &[&str] = &["?.luau", "?.lua", "?/init.luau", "?/init.lua"];
+
+// search modules in dirs before the default paths
+fn prepend_search_paths(lua: &Lua, dirs: &[&Path]) -> mlua::Result<()> {
+  let package: Table = lua.globals().get("package")?;
+  let mut path = Vec::new();
+  let mut cpath = Vec::new();
+  for dir in dirs {
+    path.extend(MODULE_PATTERNS.iter().map(|p| dir.join(p).display().to_string()));
+    cpath.push(dir.join("?.so").display().to_string());
+  }
+  path.push(package.get("path")?);
+  package.set("path", path.join(";"))?;
+  // Luau can't load C modules
+  if let Some(default_cpath) = package.get::<_, Option<String>>("cpath")? {
+    cpath.push(default_cpath);
+    package.set("cpath", cpath.join(";"))?;
+  }
+  Ok(())
+}
+
+// check access to path in sandbox mode
+fn check_access(sandbox: &Option<Sandbox>, path: &LuaString) -> mlua::Result<OsString> {
+  let path = OsString::from_vec(path.as_bytes().to_vec());
+  if let Some(sandbox) = sandbox {
+    sandbox.check(Path::new(&path), false).map_err(mlua::Error::runtime)?;
+  }
+  Ok(path)
+}
+
+// add the transformfs module with helpers to read files and codecs
+// (Luau has no io library)
+fn register_lib(lua: &Lua, sandbox: &Option<Sandbox>) -> mlua::Result<()> {
+  let lib = lua.create_table()?;
+  let s = sandbox.clone();
+  lib.set("size", lua.create_function(move |_, path: LuaString| {
+    let path = check_access(&s, &path)?;
+    Ok(fs::metadata(path).map_err(mlua::Error::external)?.len())
+  })?)?;
+  let s = sandbox.clone();
+  lib.set("read", lua.create_function(move |lua, (path, offset, size): (LuaString, u64, usize)| {
+    let path = check_access(&s, &path)?;
+    let mut file = fs::File::open(path).map_err(mlua::Error::external)?;
+    file.seek(SeekFrom::Start(offset)).map_err(mlua::Error::external)?;
+    let mut data = Vec::with_capacity(size);
+    file.take(size as u64).read_to_end(&mut data).map_err(mlua::Error::external)?;
+    lua.create_string(data)
+  })?)?;
+  codec::register(lua, &lib)?;
+  lua.globals().set("transformfs", lib)
+}
+
+/// Transform defined by a user Lua script
+pub struct LuaTransform {
+  lua: Lua,
+  user_fn: UserFn,
+  limit: CallLimit,
+  /// Pass inputs as tables with path and type
+  all_entries: bool,
+  /// User parameters as a Lua table
+  params: mlua::Value
+}
+
+impl LuaTransform {
+  /// Evaluate the user script (source or precompiled bytecode) in a fresh Lua state
+  /// and call setup with params if defined
+  pub fn load(script: &Path, options: &ScriptOptions) -> anyhow::Result<Self> {
+    let lua = match &options.sandbox {
+      // safe mode disables C modules, bytecode and debug library
+      Some(_) => Lua::new(),
+      // scripts are trusted (they can already run commands with os.execute)
+      // and unsafe mode is needed to load C modules and bytecode
+      None => unsafe { Lua::unsafe_new() }
+    };
+    let script_dir = match script.parent() {
+      Some(p) if !p.as_os_str().is_empty() => p,
+      _ => Path::new(".")
+    };
+    let mut dirs = vec![script_dir];
+    dirs.extend(options.lua_path.iter().map(PathBuf::as_path));
+    prepend_search_paths(&lua, &dirs)?;
+    register_lib(&lua, &options.sandbox)?;
+    if let Some(sandbox) = &options.sandbox {
+      sandbox.apply(&lua, &dirs)?;
+      // make libraries and globals read-only (scripts get their own globals)
+      #[cfg(feature = "luau")]
+      lua.sandbox(true)?;
+    }
+    let limit = CallLimit {
+      limit: options.time_limit,
+      ..CallLimit::default()
+    };
+    limit.install(&lua)?;
+    lua.set_app_data(limit.clone());
+    if options.sandbox.is_none() {
+      lua.set_app_data(CommandCache::new(options.command_timeout));
+    }
+    if let Some(bytes) = options.memory_limit {
+      if let Err(e) = lua.set_memory_limit(bytes) {
+        warn!("Memory limit not supported by the Lua runtime: {}", e);
+      }
+    }
+
+    let source = fs::read(script)
+      .map_err(|e| anyhow::anyhow!("Failed to read script {:?}: {}", script, e))?;
+    // chunk name starting with @ is shown as a file name in tracebacks
+    let user_fn: UserFn = limit.run(|| {
+      lua.load(&source[..])
+        .set_name(format!("@{}", script.display()))
+        .eval()
+    })?;
+    let params = lua.to_value(&options.params)?;
+    if let Some(setup) = &user_fn.setup {
+      limit.run(|| setup.call::<_, ()>(params.clone())).map_err(
+        |e| e.context("Failed to set up script")
+      )?;
+    }
+    Ok(Self {
+      lua,
+      user_fn,
+      limit,
+      all_entries: options.all_entries,
+      params
+    })
+  }
+}
+
+impl Transform for LuaTransform {
+  fn transform(&self, inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+    let inputs = inputs_to_lua(&self.lua, inputs, self.all_entries)?;
+    self.limit.run(|| self.user_fn.transform.call((inputs, self.params.clone()))).map_err(
+      |e| e.context("Invalid Output from transform")
+    )
+  }
+
+  fn incremental(&self) -> bool {
+    self.user_fn.on_change.is_some()
+  }
+
+  fn on_change(&self, added: &[Input], removed: &[Input], modified: &[Input]) -> anyhow::Result<Option<OutputPatch>> {
+    let Some(on_change) = &self.user_fn.on_change else {
+      return Ok(None);
+    };
+    let args = (
+      inputs_to_lua(&self.lua, added, self.all_entries)?,
+      inputs_to_lua(&self.lua, removed, self.all_entries)?,
+      inputs_to_lua(&self.lua, modified, self.all_entries)?
+    );
+    self.limit.run(|| on_change.call(args)).map_err(
+      |e| e.context("Invalid patch from on_change")
+    )
+  }
+}
+
+/// Load a Lua script (evaluated in a fresh Lua state on reload)
+pub struct LuaLoader {
+  pub script: PathBuf,
+  pub options: ScriptOptions
+}
+
+impl TransformLoader for LuaLoader {
+  fn name(&self) -> String {
+    self.script.display().to_string()
+  }
+
+  fn load(&self) -> anyhow::Result<Arc<dyn Transform>> {
+    Ok(Arc::new(LuaTransform::load(&self.script, &self.options)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SCRIPT: &str = r#"
+    local M = {}
+    local calls = 0
+
+    function M.transform(inputs)
+      calls = calls + 1
+      -- only the first call runs forever
+      while calls == 1 do end
+      return {
+        { path = "slow", metadata = { size = 1 }, read = function() while true do end end },
+        { path = "big", metadata = { size = 1 }, read = function()
+          local t = {}
+          for i = 1, 1e9 do
+            t[i] = string.rep("x", 1024) .. i
+          end
+        end }
+      }
+    end
+
+    return M
+  "#;
+
+  fn load(name: &str, options: ScriptOptions) -> LuaTransform {
+    let dir = std::env::temp_dir().join(format!("transformfs-lua-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("limits.lua");
+    fs::write(&script, SCRIPT).unwrap();
+    let transform = LuaTransform::load(&script, &options).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    transform
+  }
+
+  fn read(outputs: &[OutputEntry], path: &str) -> anyhow::Result<Vec<u8>> {
+    let entry = outputs.iter().find(|e| e.path == path).unwrap();
+    let OutputContent::File(file) = &entry.content else {
+      panic!("{} is not a file", path);
+    };
+    (file.read)(0, 1)
+  }
+
+  fn limit(result: anyhow::Result<impl Sized>) -> Option<LimitExceeded> {
+    match result.err()?.downcast_ref::<LimitExceeded>()? {
+      LimitExceeded::Time(limit) => Some(LimitExceeded::Time(*limit)),
+      LimitExceeded::Memory => Some(LimitExceeded::Memory)
+    }
+  }
+
+  #[test]
+  fn time_limit() {
+    let limit_ms = Duration::from_millis(100);
+    let transform = load("time", ScriptOptions { time_limit: Some(limit_ms), ..ScriptOptions::default() });
+    let start = Instant::now();
+    assert!(matches!(limit(transform.transform(&[])), Some(LimitExceeded::Time(l)) if l == limit_ms));
+    // the state is still usable after an aborted call
+    let outputs = transform.transform(&[]).unwrap();
+    assert!(matches!(limit(read(&outputs, "slow")), Some(LimitExceeded::Time(_))));
+    assert!(start.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn memory_limit() {
+    if Lua::new().set_memory_limit(1 << 30).is_err() {
+      // not supported by the runtime (e.g. LuaJIT with its own allocator)
+      return;
+    }
+    let transform = load("memory", ScriptOptions {
+      time_limit: Some(Duration::from_millis(100)),
+      memory_limit: Some(16 << 20),
+      ..ScriptOptions::default()
+    });
+    assert!(limit(transform.transform(&[])).is_some());
+    let outputs = transform.transform(&[]).unwrap();
+    assert!(matches!(limit(read(&outputs, "big")), Some(LimitExceeded::Memory)));
+  }
+}
@@ -13,34 +13,217 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-mod transformfs;
-mod utils;
-mod output;
-
-use transformfs::{Config, TransformFs};
-use std::{path::PathBuf, time::Duration};
+use transformfs::{export_dir, export_tar, load_mounts, serve_mounts, serve_worker, unmount_mounts, Builtin, MountConfig, OnError, Params};
+use std::{fs::File, io::{self, Read, Write}, path::PathBuf};
 use daemonize::Daemonize;
-use clap::Parser;
-use fuser::{self, MountOption};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
-#[command(version)]
-struct Args {
-  /// Mount point of the target transformfs
-  mount_point: PathBuf,
+#[command(version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+
+  #[command(flatten)]
+  mount: MountArgs
+}
 
+#[derive(Subcommand)]
+enum Command {
+  /// Run transform without mounting and print the output tree
+  /// (exit with error if the script fails or outputs are invalid)
+  Check(CheckArgs),
+  /// Read an output file by calling its open/read/close directly and write it to stdout
+  Cat(CatArgs),
+  /// Write all outputs into a dir (or a tar archive)
+  /// (unchanged files with the same size and mtime are skipped)
+  Export(ExportArgs),
+  /// Mount all mounts in a config file and serve them from one process
+  Up(UpArgs),
+  /// Unmount all mounts in a config file
+  Down(DownArgs),
+  /// Serve a script for --isolate over stdin/stdout
+  #[command(hide = true)]
+  Worker
+}
+
+/// Arguments to load the script and run transform
+#[derive(Args)]
+struct ScriptArgs {
   /// The input dirs/files to pass to transform function
   #[arg(short, long)]
   inputs: Vec<PathBuf>,
 
-  /// script
-  #[arg(short, long)]
-  script: PathBuf,
+  /// script (repeat to chain scripts as a pipeline where outputs of each script are inputs of the next)
+  // not required when using subcommands
+  #[arg(short = 's', long = "script", required_unless_present = "builtins")]
+  scripts: Vec<PathBuf>,
+
+  /// Built-in transform run before scripts (repeatable; chained in order), e.g.
+  /// 'filter --include *.md', concat, line-number, 'rename --regex FROM --to TO' or 'prefix PATH'
+  #[arg(short = 'b', long = "builtin", value_name = "SPEC")]
+  builtins: Vec<Builtin>,
+
+  /// Pass all entries (including dirs, symlinks and special files) to transform
+  /// as tables with path and type instead of only file paths
+  #[arg(long)]
+  all_entries: bool,
+
+  /// Parameter passed to setup and transform of the script (repeatable)
+  #[arg(long = "arg", value_name = "KEY=VALUE", value_parser = parse_param)]
+  args: Vec<(String, String)>,
+
+  /// JSON file with an object of parameters (overridden by --arg)
+  #[arg(long, value_name = "FILE")]
+  args_json: Option<PathBuf>,
+
+  /// Extra dir to search for Lua modules with require (repeatable; the script dir is always searched first)
+  #[arg(long, value_name = "DIR")]
+  lua_path: Vec<PathBuf>,
+
+  /// Run scripts in a sandbox without os.execute/io.popen
+  /// where files can only be read under inputs and written under the scratch dir
+  #[arg(long)]
+  sandbox: bool,
+
+  /// Dir scripts can read and write in sandbox mode
+  #[arg(long, value_name = "DIR", requires = "sandbox")]
+  scratch_dir: Option<PathBuf>,
+
+  /// Abort a Lua call (transform or read/open/close of a file) running longer than this in milliseconds
+  #[arg(long, value_name = "MS")]
+  lua_time_limit: Option<u64>,
+
+  /// Maximum heap size of each Lua script in MiB
+  #[arg(long, value_name = "MIB", value_parser = parse_mib)]
+  lua_memory_limit: Option<usize>,
+
+  /// Run each script in a worker process (restarted if it crashes)
+  /// so that crashes of scripts don't bring down the mount
+  #[arg(long)]
+  isolate: bool,
+
+  /// Kill a command of an output running longer than this in seconds
+  #[arg(long, value_name = "SECS", default_value_t = 60)]
+  command_timeout: u64
+}
+
+fn parse_param(s: &str) -> Result<(String, String), String> {
+  s.split_once('=')
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .ok_or_else(|| format!("invalid KEY=VALUE: no '=' found in {:?}", s))
+}
+
+// size in MiB that fits in bytes
+fn parse_mib(s: &str) -> Result<usize, String> {
+  let mib = s.parse().map_err(|e| format!("{}", e))?;
+  MountConfig { lua_memory_limit: Some(mib), ..MountConfig::default() }.config()
+    .map(|_| mib)
+    .map_err(|e| e.to_string())
+}
+
+impl ScriptArgs {
+  fn params(&self) -> anyhow::Result<Params> {
+    let mut params = match &self.args_json {
+      Some(path) => {
+        let content = std::fs::read_to_string(path)
+          .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", path, e))?;
+        match serde_json::from_str(&content)? {
+          serde_json::Value::Object(map) => map,
+          _ => anyhow::bail!("Parameters in {:?} must be a JSON object", path)
+        }
+      },
+      None => Params::new()
+    };
+    for (k, v) in &self.args {
+      params.insert(k.clone(), serde_json::Value::String(v.clone()));
+    }
+    Ok(params)
+  }
+
+  // mount config with the options of the script (others are default)
+  fn mount_config(self) -> anyhow::Result<MountConfig> {
+    Ok(MountConfig {
+      params: self.params()?,
+      scripts: self.scripts,
+      builtins: self.builtins,
+      inputs: self.inputs,
+      all_entries: self.all_entries,
+      lua_path: self.lua_path,
+      sandbox: self.sandbox,
+      scratch_dir: self.scratch_dir,
+      lua_time_limit: self.lua_time_limit,
+      lua_memory_limit: self.lua_memory_limit,
+      isolate: self.isolate,
+      command_timeout: self.command_timeout,
+      ..MountConfig::default()
+    })
+  }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+  Text,
+  Json
+}
+
+#[derive(Args)]
+struct CheckArgs {
+  #[command(flatten)]
+  script: ScriptArgs,
+
+  /// Output format of the tree
+  #[arg(short, long, value_enum, default_value_t = Format::Text)]
+  format: Format
+}
+
+#[derive(Args)]
+struct CatArgs {
+  #[command(flatten)]
+  script: ScriptArgs,
+
+  /// Path of the output file (relative to the root of output)
+  path: PathBuf,
+
+  /// Size of each read call
+  #[arg(short, long, default_value_t = 4096)]
+  chunk_size: u32,
+
+  /// Offset to start reading from
+  #[arg(long, default_value_t = 0)]
+  offset: u64,
+
+  /// Number of bytes to read (default: until the end of file by its metadata size)
+  #[arg(long)]
+  length: Option<u64>
+}
+
+#[derive(Args)]
+struct ExportArgs {
+  #[command(flatten)]
+  script: ScriptArgs,
+
+  /// Destination dir (created if not exists)
+  #[arg(required_unless_present = "tar", conflicts_with = "tar")]
+  dest: Option<PathBuf>,
+
+  /// Write a tar archive to the file instead (- for stdout)
+  #[arg(long)]
+  tar: Option<PathBuf>
+}
+
+#[derive(Args)]
+struct MountArgs {
+  /// Mount point of the target transformfs
+  #[arg(required = true)]
+  mount_point: Option<PathBuf>,
+
+  #[command(flatten)]
+  script: ScriptArgs,
 
   /// Allow other users to access the mounted fs
   #[arg(long)]
   allow_other: bool,
-
   /// Allow root user to access the mounted fs
   #[arg(long)]
   allow_root: bool,
@@ -49,11 +232,56 @@ struct Args {
   #[arg(short, long, default_value_t = u64::MAX)]
   timeout: u64,
 
+  /// Watch inputs and script with inotify and rerun transform on changes
+  #[arg(short, long)]
+  watch: bool,
+
+  /// Time in milliseconds to wait for changes to settle before rerunning transform (with --watch)
+  #[arg(long, default_value_t = 200)]
+  debounce: u64,
+
+  /// Add a hidden .transformfs control dir to inspect and reload the mount
+  /// (the mount is not read-only to allow writing to the reload file)
+  #[arg(long)]
+  control_dir: bool,
+
+  /// What to do when transform fails
+  /// (the last successful output is always kept on failed updates)
+  #[arg(long, value_enum, default_value_t = OnError::Abort)]
+  on_error: OnError,
+
+  /// Initial interval in seconds to retry a failed update (doubled on each failure, with --on-error retry)
+  #[arg(long, default_value_t = 1)]
+  retry_interval: u64,
+
+  /// Maximum interval in seconds to retry a failed update (with --on-error retry)
+  #[arg(long, default_value_t = 300)]
+  max_retry_interval: u64,
+
+  /// Add a TRANSFORM_ERROR file with the error to the root of the mount when an update fails
+  #[arg(long)]
+  error_file: bool,
+
+  /// Refuse to mount (or update) if output paths are invalid or conflict and print all problems
+  /// (otherwise problems are logged as warnings)
+  #[arg(long)]
+  strict: bool,
+
+  /// Also restrict file access of the whole process with Landlock after mounting (with --sandbox)
+  #[arg(long, requires = "sandbox", conflicts_with = "isolate")]
+  landlock: bool,
+
   /// Unmount automatically when program exists.
   /// (need --allow-root or --allow-other; auto set one if not specified)
   #[arg(short, long)]
   auto_unmount: bool,
 
+  #[command(flatten)]
+  daemon: DaemonArgs
+}
+
+#[derive(Args)]
+struct DaemonArgs {
   /// Run in foreground
   #[arg(long)]
   foreground: bool,
@@ -67,43 +295,132 @@ struct Args {
   stderr: Option<PathBuf>
 }
 
-
-fn main() -> anyhow::Result<()> {
-  env_logger::init();
-  let args = Args::parse();
-  let mut options = vec![
-    MountOption::RO,
-    MountOption::FSName("transformfs".to_string()),
-    MountOption::Subtype("transformfs".to_string()),
-  ];
-  if args.allow_other {
-    options.push(MountOption::AllowOther);
-  }
-  if args.allow_root {
-    options.push(MountOption::AllowRoot);
-  }
-  if args.auto_unmount {
-    options.push(MountOption::AutoUnmount);
-  }
-
-  if !args.foreground {
+impl DaemonArgs {
+  // run in background unless in foreground
+  fn start(self) -> anyhow::Result<()> {
+    if self.foreground {
+      return Ok(());
+    }
     let mut daemon = Daemonize::new().working_directory(".");
-    if let Some(stdout) = args.stdout {
+    if let Some(stdout) = self.stdout {
       daemon = daemon.stdout(std::fs::File::create(stdout)?);
     }
-    if let Some(stderr) = args.stderr {
+    if let Some(stderr) = self.stderr {
       daemon = daemon.stderr(std::fs::File::create(stderr)?);
     }
     daemon.start()?;
+    Ok(())
   }
+}
 
-  fuser::mount2(
-    TransformFs::init(args.inputs, args.script, Config {
-      timeout: Duration::from_secs(args.timeout)
-    })?,
-    args.mount_point,
-    &options
-  )?;
+#[derive(Args)]
+struct UpArgs {
+  /// Config file (TOML, or JSON with .json extension) listing the mounts
+  config: PathBuf,
 
+  #[command(flatten)]
+  daemon: DaemonArgs
+}
+
+#[derive(Args)]
+struct DownArgs {
+  /// Config file used to mount
+  config: PathBuf
+}
+
+
+fn check(args: CheckArgs) -> anyhow::Result<()> {
+  let fs = MountConfig { strict: true, ..args.script.mount_config()? }.builder()?.build()?;
+  let output = fs.output();
+  match args.format {
+    Format::Text => output.write_tree(&mut io::stdout().lock())?,
+    Format::Json => println!("{}", serde_json::to_string_pretty(&output.to_json())?)
+  };
   Ok(())
 }
+
+fn cat(args: CatArgs) -> anyhow::Result<()> {
+  let mut fs = args.script.mount_config()?.builder()?.build()?;
+  let Some((ino, _)) = fs.output().lookup(args.path.as_os_str()) else {
+    anyhow::bail!("Output file not found: {:?}", args.path);
+  };
+  // limited to the size of the file by reader
+  let end = args.length.map_or(u64::MAX, |len| args.offset.saturating_add(len));
+
+  let mut reader = fs.reader(ino, args.offset, end, args.chunk_size)
+    .map_err(|errno| anyhow::anyhow!("Failed to open {:?}: {}", args.path, errno))?;
+  let mut stdout = io::stdout().lock();
+  let mut buffer = vec![0; args.chunk_size as usize];
+  loop {
+    let n = reader.read(&mut buffer)
+      .map_err(|e| anyhow::anyhow!("Failed to read {:?}: {}", args.path, e))?;
+    if n == 0 {
+      break;
+    }
+    stdout.write_all(&buffer[..n])?;
+  }
+  stdout.flush()?;
+  reader.close()
+    .map_err(|errno| anyhow::anyhow!("Failed to close {:?}: {}", args.path, errno))?;
+  Ok(())
+}
+
+fn export(args: ExportArgs) -> anyhow::Result<()> {
+  let mut fs = MountConfig { strict: true, ..args.script.mount_config()? }.builder()?.build()?;
+  let stats = match (args.tar, args.dest) {
+    (Some(tar), _) if tar.as_os_str() == "-" => export_tar(&mut fs, io::stdout().lock())?,
+    (Some(tar), _) => export_tar(&mut fs, File::create(tar)?)?,
+    (None, Some(dest)) => export_dir(&mut fs, &dest)?,
+    (None, None) => unreachable!("dest or tar is required")
+  };
+  eprintln!("Exported {} file(s), skipped {} unchanged file(s), removed {} stale file(s)", stats.written, stats.skipped, stats.removed);
+  Ok(())
+}
+
+fn mount(args: MountArgs) -> anyhow::Result<()> {
+  let config = MountConfig {
+    mount_point: args.mount_point.expect("mount point is required"),
+    landlock: args.landlock,
+    timeout: args.timeout,
+    watch: args.watch,
+    debounce: args.debounce,
+    control_dir: args.control_dir,
+    on_error: args.on_error,
+    retry_interval: args.retry_interval,
+    max_retry_interval: args.max_retry_interval,
+    error_file: args.error_file,
+    strict: args.strict,
+    allow_other: args.allow_other,
+    allow_root: args.allow_root,
+    auto_unmount: args.auto_unmount,
+    ..args.script.mount_config()?
+  };
+  let builder = config.builder()?.reload_on_sighup(true);
+  args.daemon.start()?;
+  builder.mount(&config.mount_point)
+}
+
+fn up(args: UpArgs) -> anyhow::Result<()> {
+  // validate config before running in background
+  let mounts = load_mounts(&args.config)?;
+  args.daemon.start()?;
+  serve_mounts(mounts)
+}
+
+fn down(args: DownArgs) -> anyhow::Result<()> {
+  unmount_mounts(&load_mounts(&args.config)?)
+}
+
+fn main() -> anyhow::Result<()> {
+  env_logger::init();
+  let cli = Cli::parse();
+  match cli.command {
+    Some(Command::Check(args)) => check(args),
+    Some(Command::Cat(args)) => cat(args),
+    Some(Command::Export(args)) => export(args),
+    Some(Command::Up(args)) => up(args),
+    Some(Command::Down(args)) => down(args),
+    Some(Command::Worker) => serve_worker(),
+    None => mount(cli.mount)
+  }
+}
@@ -0,0 +1,283 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fs, io, path::{Path, PathBuf}, process, thread, time::Duration};
+use fuser::MountOption;
+use log::{error, info};
+use serde::{Deserialize, Deserializer};
+use crate::{builder::TransformFsBuilder, builtin::Builtin, transformfs::{Config, OnError, Params, TransformFs}};
+
+// accept a single path or a list of paths
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OneOrMany {
+    One(PathBuf),
+    Many(Vec<PathBuf>)
+  }
+  Ok(match OneOrMany::deserialize(deserializer)? {
+    OneOrMany::One(p) => vec![p],
+    OneOrMany::Many(p) => p
+  })
+}
+
+/// Options of a mount (same as the command line options)
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MountConfig {
+  pub mount_point: PathBuf,
+  /// Scripts chained as a pipeline
+  #[serde(alias = "script", deserialize_with = "one_or_many")]
+  pub scripts: Vec<PathBuf>,
+  /// Built-in transforms run before scripts (specs like the --builtin option)
+  pub builtins: Vec<Builtin>,
+  pub inputs: Vec<PathBuf>,
+  pub all_entries: bool,
+  /// Parameters passed to scripts
+  pub params: Params,
+  /// Extra dirs to search for Lua modules
+  pub lua_path: Vec<PathBuf>,
+  pub sandbox: bool,
+  pub scratch_dir: Option<PathBuf>,
+  pub landlock: bool,
+  /// Time limit of each Lua call in milliseconds
+  pub lua_time_limit: Option<u64>,
+  /// Lua heap limit in MiB
+  pub lua_memory_limit: Option<usize>,
+  pub isolate: bool,
+  /// Timeout of commands of outputs in seconds
+  pub command_timeout: u64,
+  /// Timeout in seconds
+  pub timeout: u64,
+  pub watch: bool,
+  /// Debounce time in milliseconds
+  pub debounce: u64,
+  pub control_dir: bool,
+  pub on_error: OnError,
+  /// Initial retry interval in seconds
+  pub retry_interval: u64,
+  /// Maximum retry interval in seconds
+  pub max_retry_interval: u64,
+  pub error_file: bool,
+  pub strict: bool,
+  pub allow_other: bool,
+  pub allow_root: bool,
+  pub auto_unmount: bool
+}
+
+impl Default for MountConfig {
+  fn default() -> Self {
+    Self {
+      mount_point: PathBuf::new(),
+      scripts: Vec::new(),
+      builtins: Vec::new(),
+      inputs: Vec::new(),
+      all_entries: false,
+      params: Params::new(),
+      lua_path: Vec::new(),
+      sandbox: false,
+      scratch_dir: None,
+      landlock: false,
+      lua_time_limit: None,
+      lua_memory_limit: None,
+      isolate: false,
+      command_timeout: 60,
+      timeout: u64::MAX,
+      watch: false,
+      debounce: 200,
+      control_dir: false,
+      on_error: OnError::Abort,
+      retry_interval: 1,
+      max_retry_interval: 300,
+      error_file: false,
+      strict: false,
+      allow_other: false,
+      allow_root: false,
+      auto_unmount: false
+    }
+  }
+}
+
+/// Convert a size in MiB to bytes (fails if it doesn't fit in usize)
+pub(crate) fn mib_to_bytes(mib: usize) -> anyhow::Result<usize> {
+  mib.checked_mul(1 << 20)
+    .ok_or_else(|| anyhow::anyhow!("{} MiB is too large", mib))
+}
+
+impl MountConfig {
+  pub fn config(&self) -> anyhow::Result<Config> {
+    Ok(Config {
+      timeout: Duration::from_secs(self.timeout),
+      all_entries: self.all_entries,
+      control_dir: self.control_dir,
+      on_error: self.on_error,
+      retry_interval: Duration::from_secs(self.retry_interval),
+      max_retry_interval: Duration::from_secs(self.max_retry_interval),
+      error_file: self.error_file,
+      strict: self.strict,
+      params: self.params.clone(),
+      lua_path: self.lua_path.clone(),
+      sandbox: self.sandbox,
+      scratch_dir: self.scratch_dir.clone(),
+      landlock: self.landlock,
+      lua_time_limit: self.lua_time_limit.map(Duration::from_millis),
+      lua_memory_limit: self.lua_memory_limit.map(mib_to_bytes).transpose()?,
+      isolate: self.isolate,
+      command_timeout: Some(Duration::from_secs(self.command_timeout))
+    })
+  }
+
+  pub fn mount_options(&self) -> Vec<MountOption> {
+    let mut options = Vec::new();
+    if self.allow_other {
+      options.push(MountOption::AllowOther);
+    }
+    if self.allow_root {
+      options.push(MountOption::AllowRoot);
+    }
+    if self.auto_unmount {
+      options.push(MountOption::AutoUnmount);
+    }
+    options
+  }
+
+  pub fn builder(&self) -> anyhow::Result<TransformFsBuilder> {
+    let builder = self.builtins.iter()
+      .fold(TransformFs::builder(), |builder, builtin| builder.transform(builtin.clone()));
+    Ok(self.scripts.iter()
+      .fold(builder, |builder, script| builder.script(script))
+      .inputs(self.inputs.iter().cloned())
+      .config(self.config()?)
+      .mount_options(self.mount_options())
+      .watch(self.watch.then(|| Duration::from_millis(self.debounce))))
+  }
+
+  // resolve relative paths against dir
+  fn resolve(&mut self, dir: &Path) {
+    self.mount_point = dir.join(&self.mount_point);
+    for p in self.scripts.iter_mut().chain(self.inputs.iter_mut()).chain(self.lua_path.iter_mut()).chain(self.scratch_dir.iter_mut()) {
+      *p = dir.join(&*p);
+    }
+  }
+}
+
+/// Config file with multiple mounts
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MountsFile {
+  #[serde(rename = "mount", alias = "mounts")]
+  mounts: Vec<MountConfig>
+}
+
+/// Load mounts from a JSON (by .json extension) or TOML config file
+///
+/// Relative paths are resolved against the dir of the config file
+pub fn load_mounts(path: &Path) -> anyhow::Result<Vec<MountConfig>> {
+  let content = fs::read_to_string(path)
+    .map_err(|e| anyhow::anyhow!("Failed to read config {:?}: {}", path, e))?;
+  let file: MountsFile = if path.extension().is_some_and(|e| e == "json") {
+    serde_json::from_str(&content)?
+  } else {
+    toml::from_str(&content)?
+  };
+  let dir = path.parent().unwrap_or(Path::new("."));
+  let mut mounts = file.mounts;
+  for (i, m) in mounts.iter_mut().enumerate() {
+    if m.mount_point.as_os_str().is_empty() {
+      anyhow::bail!("Mount {} in {:?}: mount_point is required", i + 1, path);
+    }
+    if m.scripts.is_empty() && m.builtins.is_empty() {
+      anyhow::bail!("Mount {} in {:?}: script or builtins is required", i + 1, path);
+    }
+    if m.landlock && m.isolate {
+      anyhow::bail!("Mount {} in {:?}: landlock can't be used with isolate", i + 1, path);
+    }
+    if let Err(err) = m.config() {
+      anyhow::bail!("Mount {} in {:?}: invalid lua_memory_limit: {}", i + 1, path, err);
+    }
+    m.resolve(dir);
+  }
+  Ok(mounts)
+}
+
+/// Serve all mounts from this process until all of them are unmounted
+pub fn serve_mounts(mounts: Vec<MountConfig>) -> anyhow::Result<()> {
+  let builders = mounts.into_iter()
+    .map(|m| Ok((m.builder()?.reload_on_sighup(true), m.mount_point)))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  let handles: Vec<_> = builders.into_iter()
+    .map(|(builder, mount_point)| {
+      info!("Mount {:?}", mount_point);
+      let handle = thread::spawn({
+        let mount_point = mount_point.clone();
+        move || builder.mount(mount_point)
+      });
+      (mount_point, handle)
+    })
+    .collect();
+
+  let mut failed = 0;
+  for (mount_point, handle) in handles {
+    match handle.join() {
+      Ok(Ok(())) => info!("Unmounted {:?}", mount_point),
+      Ok(Err(err)) => {
+        error!("Failed to mount {:?}: {:#}", mount_point, err);
+        failed += 1;
+      },
+      Err(_) => {
+        error!("Mount {:?} panicked", mount_point);
+        failed += 1;
+      }
+    };
+  }
+  if failed > 0 {
+    anyhow::bail!("{} mount(s) failed", failed);
+  }
+  Ok(())
+}
+
+/// Programs to unmount FUSE mounts (fuse3 first)
+const FUSERMOUNT: [&str; 2] = ["fusermount3", "fusermount"];
+
+// unmount with the first fusermount program found
+fn unmount(mount_point: &Path) -> anyhow::Result<()> {
+  for program in FUSERMOUNT {
+    match process::Command::new(program).arg("-u").arg(mount_point).status() {
+      Ok(s) if s.success() => return Ok(()),
+      Ok(s) => anyhow::bail!("{} exited with {}", program, s),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+      Err(err) => anyhow::bail!("Failed to run {}: {}", program, err)
+    };
+  }
+  anyhow::bail!("{} not found", FUSERMOUNT.join(" or "))
+}
+
+/// Unmount all mounts with fusermount3 (or fusermount)
+pub fn unmount_mounts(mounts: &[MountConfig]) -> anyhow::Result<()> {
+  let mut failed = 0;
+  for m in mounts {
+    match unmount(&m.mount_point) {
+      Ok(()) => info!("Unmounted {:?}", m.mount_point),
+      Err(err) => {
+        error!("Failed to unmount {:?}: {:#}", m.mount_point, err);
+        failed += 1;
+      }
+    };
+  }
+  if failed > 0 {
+    anyhow::bail!("Failed to unmount {} mount(s)", failed);
+  }
+  Ok(())
+}
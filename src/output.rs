@@ -13,36 +13,90 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{collections::HashMap, ffi::OsString, os::unix::ffi::{OsStrExt, OsStringExt}, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, ffi::{OsStr, OsString}, fmt, io::{self, Write}, os::unix::ffi::OsStrExt, path::{Component, Path, PathBuf}, sync::Arc};
 
-use fuser::FUSE_ROOT_ID;
-use log::{debug, error, info};
-use mlua::{FromLua, Function, Lua, String as LuaString};
+use fuser::{Notifier, FUSE_ROOT_ID};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::utils;
+use crate::transformfs::Config;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OutputFileMetadata {
   pub size: u64,
-  pub block_size: Option<u32>
+  pub block_size: Option<u32>,
+  /// Modification time in seconds since Unix epoch
+  pub mtime: Option<u64>
 }
 
-impl FromLua for OutputFileMetadata {
-  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
-    let mlua::Value::Table(table) = &value else {
-      return Err(mlua::Error::runtime("OutputFileMetadata must be a Lua table"));
-    };
-    Ok(OutputFileMetadata {
-      size: table.get("size")?,
-      block_size: table.get("block_size")?
-    })
+impl OutputFileMetadata {
+  pub fn new(size: u64) -> Self {
+    Self {
+      size,
+      block_size: None,
+      mtime: None
+    }
   }
 }
 
+/// Read data at offset with the max size
+pub type ReadFn = Box<dyn Fn(u64, u32) -> anyhow::Result<Vec<u8>> + Send + Sync>;
+/// Called when a file is opened or closed
+pub type CallbackFn = Box<dyn Fn() -> anyhow::Result<()> + Send + Sync>;
+/// Size of a file once it is known
+pub type SizeFn = Box<dyn Fn() -> Option<u64> + Send + Sync>;
+
 pub struct OutputFile {
   pub metadata: OutputFileMetadata,
-  pub open: Option<Function>,
-  pub close: Option<Function>,
-  pub read: Function
+  pub open: Option<CallbackFn>,
+  pub close: Option<CallbackFn>,
+  pub read: ReadFn,
+  /// Size only known after opening (metadata size until then)
+  /// such files are opened with direct IO so that reads are not limited by the stale size
+  pub dynamic_size: Option<SizeFn>
+}
+
+impl fmt::Debug for OutputFile {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("OutputFile")
+      .field("size", &self.size())
+      .finish_non_exhaustive()
+  }
+}
+
+impl OutputFile {
+  pub fn new(metadata: OutputFileMetadata, read: impl Fn(u64, u32) -> anyhow::Result<Vec<u8>> + Send + Sync + 'static) -> Self {
+    Self {
+      metadata,
+      open: None,
+      close: None,
+      read: Box::new(read),
+      dynamic_size: None
+    }
+  }
+
+  pub fn with_dynamic_size(mut self, size: impl Fn() -> Option<u64> + Send + Sync + 'static) -> Self {
+    self.dynamic_size = Some(Box::new(size));
+    self
+  }
+
+  /// Current size of the file
+  pub fn size(&self) -> u64 {
+    self.dynamic_size.as_ref()
+      .and_then(|size| size())
+      .unwrap_or(self.metadata.size)
+  }
+
+  pub fn on_open(mut self, open: impl Fn() -> anyhow::Result<()> + Send + Sync + 'static) -> Self {
+    self.open = Some(Box::new(open));
+    self
+  }
+
+  pub fn on_close(mut self, close: impl Fn() -> anyhow::Result<()> + Send + Sync + 'static) -> Self {
+    self.close = Some(Box::new(close));
+    self
+  }
 }
 
 pub struct OutputDirEntry {
@@ -51,9 +105,64 @@ pub struct OutputDirEntry {
   pub kind: fuser::FileType
 }
 
+/// Virtual file in the control dir
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlFile {
+  Status,
+  LastError,
+  Stats,
+  Outputs,
+  Reload,
+  /// Error of the failed update at the root of output
+  TransformError
+}
+
+impl ControlFile {
+  /// Files in the control dir
+  pub const ALL: [ControlFile; 5] = [
+    ControlFile::Status,
+    ControlFile::LastError,
+    ControlFile::Stats,
+    ControlFile::Outputs,
+    ControlFile::Reload
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      ControlFile::Status => "status",
+      ControlFile::LastError => "last_error",
+      ControlFile::Stats => "stats",
+      ControlFile::Outputs => "outputs.json",
+      ControlFile::Reload => "reload",
+      ControlFile::TransformError => ERROR_FILE
+    }
+  }
+
+  pub fn writable(&self) -> bool {
+    *self == ControlFile::Reload
+  }
+}
+
+/// Name of the control dir at the root of output
+pub const CONTROL_DIR: &str = ".transformfs";
+/// Name of the error file at the root of output
+pub const ERROR_FILE: &str = "TRANSFORM_ERROR";
+
 pub enum OutputContent {
-  File(OutputFile),
-  Dir(Vec<OutputDirEntry>)
+  /// Shared with open file handles so it stays alive until released
+  File(Arc<OutputFile>),
+  Dir(Vec<OutputDirEntry>),
+  /// Generated by transformfs instead of user script
+  Control(ControlFile)
+}
+
+impl OutputContent {
+  pub fn kind(&self) -> fuser::FileType {
+    match self {
+      OutputContent::File(_) | OutputContent::Control(_) => fuser::FileType::RegularFile,
+      OutputContent::Dir(_) => fuser::FileType::Directory
+    }
+  }
 }
 
 pub struct OutputEntry {
@@ -61,162 +170,696 @@ pub struct OutputEntry {
   pub content: OutputContent
 }
 
-impl FromLua for OutputEntry {
-  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
-    let mlua::Value::Table(table) = &value else {
-      return Err(mlua::Error::runtime("File must be a Lua table"));
-    };
-    let path = OsString::from_vec(
-      table.get::<_, LuaString>("path")?.as_bytes().to_vec()
-    );
-    // normalize path
-    let path = Path::new(&path).as_os_str().to_os_string();
-    Ok(OutputEntry {
-      path,
-      content: OutputContent::File(OutputFile {
-        metadata: table.get("metadata")?,
-        open: table.get("open")?,
-        close: table.get("close")?,
-        read: table.get("read")?
-      })
-    })
+impl OutputEntry {
+  /// Output file at a path relative to the root of output
+  pub fn file(path: impl Into<OsString>, file: OutputFile) -> Self {
+    Self {
+      path: path.into(),
+      content: OutputContent::File(Arc::new(file))
+    }
+  }
+}
+
+/// Problem of output paths found by validation
+#[derive(Debug)]
+pub enum Conflict {
+  /// Same file path returned more than once
+  Duplicate(OsString),
+  /// Path used both as a file and as a dir
+  FileDir(OsString),
+  /// Empty path or path with empty components
+  EmptyComponent(OsString),
+  /// Path with `..` components
+  ParentComponent(OsString),
+  /// Absolute path
+  Absolute(OsString),
+  /// Path reserved for files generated by transformfs
+  Reserved(OsString)
+}
+
+impl fmt::Display for Conflict {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Conflict::Duplicate(p) => write!(f, "duplicate file path: {:?}", p),
+      Conflict::FileDir(p) => write!(f, "path used as both file and dir: {:?}", p),
+      Conflict::EmptyComponent(p) => write!(f, "empty path component: {:?}", p),
+      Conflict::ParentComponent(p) => write!(f, "'..' path component: {:?}", p),
+      Conflict::Absolute(p) => write!(f, "absolute path: {:?}", p),
+      Conflict::Reserved(p) => write!(f, "reserved path: {:?}", p)
+    }
+  }
+}
+
+// components of a path in output as given (ignoring a leading slash)
+fn raw_components(path: &OsStr) -> Vec<&[u8]> {
+  let bytes = path.as_bytes();
+  let mut components = bytes.split(|b| *b == b'/');
+  if bytes.starts_with(b"/") {
+    components.next();
+  }
+  components.collect()
+}
+
+/// Find all problems of output paths
+pub fn validate(entries: &[OutputEntry]) -> Vec<Conflict> {
+  let mut conflicts = Vec::new();
+  // normalized path of files to their raw path
+  let mut files = HashMap::new();
+  let mut dirs = HashSet::new();
+
+  for e in entries {
+    let bytes = e.path.as_bytes();
+    if bytes.starts_with(b"/") {
+      conflicts.push(Conflict::Absolute(e.path.clone()));
+    }
+    let components = raw_components(&e.path);
+    if components.iter().any(|c| c.is_empty()) {
+      conflicts.push(Conflict::EmptyComponent(e.path.clone()));
+      continue;
+    }
+    if components.iter().any(|c| *c == b"..") {
+      conflicts.push(Conflict::ParentComponent(e.path.clone()));
+      continue;
+    }
+
+    let path = normalize_path(&e.path);
+    if path.starts_with(Path::new("/").join(CONTROL_DIR)) {
+      conflicts.push(Conflict::Reserved(e.path.clone()));
+    }
+    dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+    if files.insert(path, &e.path).is_some() {
+      conflicts.push(Conflict::Duplicate(e.path.clone()));
+    }
+  }
+
+  let mut file_dirs: Vec<_> = files.into_iter()
+    .filter(|(path, _)| dirs.contains(path))
+    .map(|(_, raw)| raw.clone())
+    .collect();
+  file_dirs.sort();
+  conflicts.extend(file_dirs.into_iter().map(Conflict::FileDir));
+  conflicts
+}
+
+/// Validate output paths and fail in strict mode or log problems otherwise
+pub fn check_conflicts(entries: &[OutputEntry], config: &Config) -> anyhow::Result<()> {
+  report_conflicts(validate(entries), config)
+}
+
+/// Fail in strict mode or log problems otherwise
+pub fn report_conflicts(conflicts: Vec<Conflict>, config: &Config) -> anyhow::Result<()> {
+  if conflicts.is_empty() {
+    return Ok(());
+  }
+  if config.strict {
+    let report = conflicts.iter()
+      .map(|c| format!("  - {}", c))
+      .collect::<Vec<_>>()
+      .join("\n");
+    anyhow::bail!("Invalid output ({} problem(s)):\n{}", conflicts.len(), report);
+  }
+  for c in &conflicts {
+    warn!("Invalid output: {}", c);
+  }
+  Ok(())
+}
+
+/// Changes to output returned by the incremental hook
+pub struct OutputPatch {
+  pub add: Vec<OutputEntry>,
+  pub remove: Vec<OsString>
+}
+
+/// Kernel cache entries to invalidate after output changes
+#[derive(Default)]
+pub struct Invalidation {
+  /// Inodes whose attributes or content changed
+  pub inodes: Vec<u64>,
+  /// Dir entries (parent inode and name) added or removed
+  pub entries: Vec<(u64, OsString)>
+}
+
+impl Invalidation {
+  pub fn notify(&self, notifier: &Notifier) {
+    for (parent, name) in &self.entries {
+      if let Err(err) = notifier.inval_entry(*parent, name) {
+        debug!("Failed to invalidate entry {:?}: {}", name, err);
+      }
+    }
+    for ino in &self.inodes {
+      if let Err(err) = notifier.inval_inode(*ino, 0, 0) {
+        debug!("Failed to invalidate inode {}: {}", ino, err);
+      }
+    }
   }
 }
 
+// normalize path to an absolute path in output
+fn normalize_path(path: &OsStr) -> PathBuf {
+  let mut p = PathBuf::new();
+  p.push("/");
+  p.push(path);
+  p.components().collect()
+}
+
 pub struct Output {
   /// Map inode to output
   pub inode_map: HashMap<u64, OutputEntry>,
   /// Map file path to inode
   pub path_map: HashMap<OsString, u64>,
+  /// Next inode to allocate (inodes are never reused once retired)
+  next_ino: u64,
+  /// Inodes of the previous output to keep for the same paths when rebuilding
+  prev_inodes: HashMap<OsString, (u64, fuser::FileType)>,
+  /// Inodes of files with open handles
+  /// (their paths get new inodes when replaced so that the kernel doesn't cache new content for old handles)
+  open_inodes: HashSet<u64>
+}
+
+impl Default for Output {
+  fn default() -> Self {
+    Output::new()
+  }
 }
 
 impl Output {
   fn lookup_path_with_map<'a>(inode_map: &'a HashMap<u64, OutputEntry>, path_map: &'a HashMap<OsString, u64>, path: &OsString) -> Option<(u64, &'a OutputEntry)> {
     path_map.get(path)
-      .map(|ino| (ino.clone(), inode_map.get(ino).expect("Path in path_map but ino not in inode_map")))
+      .map(|ino| (*ino, inode_map.get(ino).expect("Path in path_map but ino not in inode_map")))
   }
 
   fn lookup_path_with_map_mut<'a>(inode_map: &'a mut HashMap<u64, OutputEntry>, path_map: &'a HashMap<OsString, u64>, path: &OsString) -> Option<(u64, &'a mut OutputEntry)> {
     path_map.get(path)
-      .map(|ino| (ino.clone(), inode_map.get_mut(ino).expect("Path in path_map but ino not in inode_map")))
+      .map(|ino| (*ino, inode_map.get_mut(ino).expect("Path in path_map but ino not in inode_map")))
   }
 
   fn append_dir_entry(inode_map: &mut HashMap<u64, OutputEntry>, path_map: &HashMap<OsString, u64>, dir_path: &OsString, entry: OutputDirEntry) -> bool {
-    let Some((_, parent_entry)) = Output::lookup_path_with_map_mut(inode_map, &path_map, &dir_path) else {
+    let Some((_, parent_entry)) = Output::lookup_path_with_map_mut(inode_map, path_map, dir_path) else {
       error!("Appending to non-existent dir: {:?}", dir_path);
       return false;
     };
     let OutputContent::Dir(parent_dir) = &mut parent_entry.content else {
-      panic!("Appending to a file: {:?}", dir_path);
+      error!("Appending to a file: {:?}", dir_path);
+      return false;
     };
     parent_dir.push(entry);
-    return true;
+    true
   }
 
   pub fn lookup_path(&self, path: &OsString) -> Option<(u64, &OutputEntry)> {
     Output::lookup_path_with_map(&self.inode_map, &self.path_map, path)
   }
 
-  // transform input to output
-  pub fn init(lua: &Lua, function: &Function, input: &Vec<PathBuf>) -> anyhow::Result<Output> {
-    let mut inode_map: HashMap<u64, OutputEntry> = HashMap::new();
-    let mut path_map = HashMap::new();
-
-    // Expand input to input files as Lua doesn't support dir
-    let input_files = input.iter()
-      .flat_map(utils::read_files)
-      .map(|v| lua.create_string(v.as_bytes()))
-      .collect::<Result<Vec<LuaString>, _>>()?;
+  /// Look up an entry by a path relative to the root of output
+  pub fn lookup(&self, path: &OsStr) -> Option<(u64, &OutputEntry)> {
+    self.lookup_path(&normalize_path(path).into_os_string())
+  }
 
-    let output_files: Vec<OutputEntry> = function.call(input_files).map_err(
-      |e| anyhow::anyhow!("Invalid Output from transform: {}", e)
-    )?;
-    // root entry
+  /// Empty output with only the root dir
+  pub fn new() -> Output {
+    let mut inode_map = HashMap::new();
+    let mut path_map = HashMap::new();
     inode_map.insert(FUSE_ROOT_ID, OutputEntry {
       path: OsString::from("/"),
       content: OutputContent::Dir(Vec::new())
     });
     path_map.insert(OsString::from("/"), FUSE_ROOT_ID);
-    info!("Output {} file(s)", output_files.len());
+    Output {
+      inode_map,
+      path_map,
+      next_ino: FUSE_ROOT_ID + 1,
+      prev_inodes: HashMap::new(),
+      open_inodes: HashSet::new()
+    }
+  }
 
-    // current inode
-    let mut ino = FUSE_ROOT_ID + 1;
-    for f in output_files {
-      debug!("Processing output file: {:?}", f.path);
-      let mut path = PathBuf::new();
-      // normallize
-      path.push("/");
-      path.push(f.path.clone());
-      let mut it = path.components().peekable();
-      let mut cur_path = PathBuf::new();
-
-      while let Some(c) = it.next() {
-        let parent_str = cur_path.clone().into_os_string();
-        cur_path.push(c);
-        let cur_path_str = cur_path.as_os_str().to_os_string();
-        if c == std::path::Component::RootDir {
-          continue;
+  /// Empty output that keeps the inodes of entries in the previous output
+  fn new_from(prev: &Output) -> Output {
+    let mut output = Output::new();
+    output.next_ino = prev.next_ino;
+    output.prev_inodes = prev.path_map.iter()
+      .filter(|(_, ino)| !prev.open_inodes.contains(ino))
+      .filter_map(|(path, ino)| {
+        Some((path.clone(), (*ino, prev.inode_map.get(ino)?.content.kind())))
+      })
+      .collect();
+    output
+  }
+
+  /// Set inodes of files with open handles
+  pub fn set_open_inodes(&mut self, inodes: HashSet<u64>) {
+    self.open_inodes = inodes;
+  }
+
+  // unlink an open file so that its path gets a new inode
+  fn detach_open_file(&mut self, parent_path: &OsString, path: &OsString, name: &OsStr, inval: &mut Invalidation) {
+    let Some(&ino) = self.path_map.get(path).filter(|ino| self.open_inodes.contains(ino)) else {
+      return;
+    };
+    if !matches!(self.inode_map.get(&ino), Some(OutputEntry { content: OutputContent::File(_), .. })) {
+      return;
+    }
+    self.path_map.remove(path);
+    self.inode_map.remove(&ino);
+    inval.inodes.push(ino);
+    if let Some((parent_ino, OutputEntry { content: OutputContent::Dir(entries), .. })) =
+      Output::lookup_path_with_map_mut(&mut self.inode_map, &self.path_map, parent_path) {
+      entries.retain(|e| e.ino != ino);
+      inval.inodes.push(parent_ino);
+      inval.entries.push((parent_ino, name.to_os_string()));
+    }
+  }
+
+  // reuse the previous inode of the path if it has the same kind
+  fn alloc_ino(&mut self, path: &OsString, kind: fuser::FileType) -> u64 {
+    match self.prev_inodes.get(path) {
+      Some((ino, k)) if *k == kind => *ino,
+      _ => {
+        self.next_ino += 1;
+        self.next_ino - 1
+      }
+    }
+  }
+
+  // add a new entry and link it to its parent dir
+  fn add_entry(&mut self, parent_path: &OsString, path: OsString, name: &OsStr, content: OutputContent, inval: &mut Invalidation) -> bool {
+    let kind = content.kind();
+    let ino = self.alloc_ino(&path, kind);
+    let ok = Output::append_dir_entry(
+      &mut self.inode_map,
+      &self.path_map,
+      parent_path,
+      OutputDirEntry {
+        ino,
+        kind,
+        name: name.to_os_string()
+      }
+    );
+    if !ok {
+      return false;
+    }
+
+    let parent_ino = self.path_map[parent_path];
+    inval.entries.push((parent_ino, name.to_os_string()));
+    inval.inodes.push(parent_ino);
+    self.inode_map.insert(ino, OutputEntry { path: path.clone(), content });
+    self.path_map.insert(path, ino);
+    true
+  }
+
+  /// Add a file to output and create its parent dirs.
+  /// An existing file at the same path is replaced in place.
+  pub fn insert(&mut self, f: OutputEntry, inval: &mut Invalidation) -> bool {
+    debug!("Processing output file: {:?}", f.path);
+    // `..` would create a literal dir named `..` in output
+    if raw_components(&f.path).iter().any(|c| c.is_empty() || *c == b"..") {
+      error!("Invalid output path: {:?}", f.path);
+      return false;
+    }
+    let path = normalize_path(&f.path);
+    let mut it = path.components().peekable();
+    let mut cur_path = PathBuf::new();
+
+    while let Some(c) = it.next() {
+      let parent_str = cur_path.clone().into_os_string();
+      cur_path.push(c);
+      let cur_path_str = cur_path.as_os_str().to_os_string();
+      if c == Component::RootDir {
+        continue;
+      }
+
+      if it.peek().is_none() {
+        self.detach_open_file(&parent_str, &cur_path_str, c.as_os_str(), inval);
+        if let Some((ino, entry)) = Output::lookup_path_with_map_mut(&mut self.inode_map, &self.path_map, &cur_path_str) {
+          if let OutputContent::Dir(_) = entry.content {
+            error!("Failed to add file {:?}: used by a dir", cur_path);
+            return false;
+          }
+          if matches!(entry.content, OutputContent::Control(_)) && !matches!(f.content, OutputContent::Control(_)) {
+            error!("Failed to add file {:?}: reserved by transformfs", cur_path);
+            return false;
+          }
+          debug!("Replacing file {:?}", cur_path);
+          entry.content = f.content;
+          inval.inodes.push(ino);
+          return true;
         }
+        return self.add_entry(&parent_str, cur_path_str, c.as_os_str(), f.content, inval);
+      }
 
-        if it.peek().is_none() {
-          let ok = Output::append_dir_entry(
-            &mut inode_map,
-            &path_map,
-            &parent_str,
-            OutputDirEntry {
-              ino,
-              kind: fuser::FileType::RegularFile,
-              name: c.as_os_str().to_os_string()
-            }
-          );
+      match self.lookup_path(&cur_path_str) {
+        Some((_, entry)) => {
+          if entry.content.kind() != fuser::FileType::Directory {
+            error!("Failed to add dir {:?}: used by a file", cur_path);
+            return false;
+          }
+        },
+        None => {
+          let ok = self.add_entry(&parent_str, cur_path_str, c.as_os_str(), OutputContent::Dir(Vec::new()), inval);
           if !ok {
-            break;
+            return false;
           }
-
-          inode_map.insert(ino, f);
-          path_map.insert(cur_path_str, ino);
-          ino += 1;
-          break;
         }
-        else {
-          let entry_ino = match path_map.get(&cur_path_str) {
-            Some(i) => i.clone(),
-            None => {
-              path_map.insert(cur_path_str.clone(), ino);
-              let ok = Output::append_dir_entry(
-                &mut inode_map,
-                &path_map,
-                &parent_str,
-                OutputDirEntry {
-                  ino,
-                  kind: fuser::FileType::Directory,
-                  name: c.as_os_str().to_os_string()
-                }
-              );
-              if !ok {
-                break;
-              }
-
-              ino += 1;
-              ino - 1
-            }
-          };
-          let entry = inode_map.entry(entry_ino).or_insert_with(|| OutputEntry {
-            path: cur_path_str.clone(),
-            content: OutputContent::Dir(Vec::new())
-          });
-          if let OutputContent::File(_) = entry.content {
-            error!("Failed to add dir {:?}: used by a file", cur_path);
-            break;
+      };
+    }
+    error!("Invalid output path: {:?}", f.path);
+    false
+  }
+
+  /// Remove a file from output.
+  /// Parent dirs left empty are removed as well.
+  /// Control files are ignored (see `remove_control`)
+  pub fn remove(&mut self, path: &OsStr, inval: &mut Invalidation) -> bool {
+    let cur_path = normalize_path(path);
+    match self.lookup_path(&cur_path.as_os_str().to_os_string()) {
+      Some((_, OutputEntry { content: OutputContent::File(_), .. })) => {},
+      Some((_, OutputEntry { content: OutputContent::Control(_), .. })) => {
+        warn!("Not removing {:?}: reserved by transformfs", path);
+        return false;
+      },
+      _ => {
+        error!("Failed to remove {:?}: not a file", path);
+        return false;
+      }
+    };
+    self.remove_entry(cur_path, inval);
+    true
+  }
+
+  /// Remove a control file from output
+  pub fn remove_control(&mut self, path: &OsStr, inval: &mut Invalidation) -> bool {
+    let cur_path = normalize_path(path);
+    match self.lookup_path(&cur_path.as_os_str().to_os_string()) {
+      Some((_, OutputEntry { content: OutputContent::Control(_), .. })) => {
+        self.remove_entry(cur_path, inval);
+        true
+      },
+      _ => false
+    }
+  }
+
+  // remove an entry and its parent dirs left empty
+  fn remove_entry(&mut self, mut cur_path: PathBuf, inval: &mut Invalidation) {
+
+    while let Some(parent_path) = cur_path.parent().map(|p| p.as_os_str().to_os_string()) {
+      let Some(ino) = self.path_map.remove(cur_path.as_os_str()) else {
+        break;
+      };
+      self.inode_map.remove(&ino);
+      inval.inodes.push(ino);
+
+      let Some((parent_ino, parent_entry)) = Output::lookup_path_with_map_mut(&mut self.inode_map, &self.path_map, &parent_path) else {
+        break;
+      };
+      inval.inodes.push(parent_ino);
+      if let Some(name) = cur_path.file_name() {
+        inval.entries.push((parent_ino, name.to_os_string()));
+      }
+      let OutputContent::Dir(entries) = &mut parent_entry.content else {
+        break;
+      };
+      entries.retain(|e| e.ino != ino);
+      if !entries.is_empty() || parent_ino == FUSE_ROOT_ID {
+        break;
+      }
+      cur_path = PathBuf::from(parent_path);
+    }
+  }
+
+  /// Find problems of a patch against this output (and within the patch itself)
+  pub fn validate_patch(&self, patch: &OutputPatch) -> Vec<Conflict> {
+    let mut conflicts = validate(&patch.add);
+    let removed: HashSet<_> = patch.remove.iter().map(|p| normalize_path(p)).collect();
+    // files remaining in output after removals
+    let is_file = |path: &Path| {
+      !removed.contains(path) && matches!(
+        self.lookup_path(&path.as_os_str().to_os_string()),
+        Some((_, e)) if e.content.kind() != fuser::FileType::Directory
+      )
+    };
+
+    for e in &patch.add {
+      let path = normalize_path(&e.path);
+      match self.lookup_path(&path.as_os_str().to_os_string()) {
+        // paths under the control dir are reported by validate already
+        Some((_, OutputEntry { content: OutputContent::Control(_), .. }))
+          if !path.starts_with(Path::new("/").join(CONTROL_DIR)) => {
+          conflicts.push(Conflict::Reserved(e.path.clone()));
+        },
+        // a dir stays unless all files under it are removed
+        Some((_, OutputEntry { content: OutputContent::Dir(_), .. })) => {
+          let used = self.path_map.keys()
+            .map(Path::new)
+            .any(|p| p != path && p.starts_with(&path) && is_file(p));
+          if used {
+            conflicts.push(Conflict::FileDir(e.path.clone()));
           }
+        },
+        _ => {}
+      };
+      if path.ancestors().skip(1).any(is_file) {
+        conflicts.push(Conflict::FileDir(e.path.clone()));
+      }
+    }
+    conflicts
+  }
+
+  /// Apply changes from the incremental hook
+  pub fn patch(&mut self, patch: OutputPatch) -> Invalidation {
+    let mut inval = Invalidation::default();
+    for path in patch.remove {
+      self.remove(&path, &mut inval);
+    }
+    info!("Patch output: {} file(s) added or replaced", patch.add.len());
+    for f in patch.add {
+      self.insert(f, &mut inval);
+    }
+    inval
+  }
+
+  /// Add the control dir with its virtual files
+  pub fn insert_control_dir(&mut self) {
+    let mut inval = Invalidation::default();
+    for f in ControlFile::ALL {
+      self.insert(OutputEntry {
+        path: Path::new(CONTROL_DIR).join(f.name()).into_os_string(),
+        content: OutputContent::Control(f)
+      }, &mut inval);
+    }
+  }
+
+  /// All entries except control files as a JSON list (sorted by path)
+  pub fn to_json(&self) -> serde_json::Value {
+    let control_path = Path::new("/").join(CONTROL_DIR);
+    let mut paths: Vec<_> = self.path_map.iter()
+      .filter(|(path, _)| !Path::new(path).starts_with(&control_path))
+      .collect();
+    paths.sort();
+    paths.into_iter()
+      .filter_map(|(path, ino)| {
+        let (kind, size) = match &self.inode_map.get(ino)?.content {
+          OutputContent::File(f) => ("file", Some(f.size())),
+          OutputContent::Dir(_) => ("dir", None),
+          OutputContent::Control(_) => return None
+        };
+        Some(json!({
+          "path": path.to_string_lossy(),
+          "ino": ino,
+          "kind": kind,
+          "size": size
+        }))
+      })
+      .collect()
+  }
+
+  /// Write output as an indented tree (entries sorted by name)
+  pub fn write_tree(&self, w: &mut impl Write) -> io::Result<()> {
+    self.write_tree_entry(w, FUSE_ROOT_ID, OsStr::new(""), 0)
+  }
+
+  fn write_tree_entry(&self, w: &mut impl Write, ino: u64, name: &OsStr, depth: usize) -> io::Result<()> {
+    let Some(entry) = self.inode_map.get(&ino) else {
+      return Ok(());
+    };
+    let indent = "  ".repeat(depth);
+    match &entry.content {
+      OutputContent::Dir(entries) => {
+        writeln!(w, "{}{}/", indent, name.to_string_lossy())?;
+        let mut entries: Vec<_> = entries.iter().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for e in entries {
+          self.write_tree_entry(w, e.ino, &e.name, depth + 1)?;
         }
+      },
+      OutputContent::File(f) => {
+        writeln!(w, "{}{} ({} bytes)", indent, name.to_string_lossy(), f.size())?;
+      },
+      OutputContent::Control(_) => {}
+    };
+    Ok(())
+  }
+
+  /// Invalidate every entry in output
+  pub fn invalidate_all(&self) -> Invalidation {
+    let mut inval = Invalidation::default();
+    for (ino, entry) in &self.inode_map {
+      if let OutputContent::Dir(entries) = &entry.content {
+        inval.entries.extend(entries.iter().map(|e| (*ino, e.name.clone())));
       }
+      inval.inodes.push(*ino);
     }
+    inval
+  }
 
-    Ok(Output {
-      inode_map,
-      path_map
-    })
+  // build output from transformed files and the control dir if enabled
+  // (inodes of paths in the previous output are kept)
+  pub fn init(output_files: Vec<OutputEntry>, config: &Config, prev: Option<&Output>) -> anyhow::Result<Output> {
+    info!("Output {} file(s)", output_files.len());
+    check_conflicts(&output_files, config)?;
+
+    let mut output = prev.map_or_else(Output::new, Output::new_from);
+    // before outputs so that they can't take its paths
+    // and before the previous inodes are dropped so that it keeps them
+    if config.control_dir {
+      output.insert_control_dir();
+    }
+    let mut inval = Invalidation::default();
+    for f in output_files {
+      output.insert(f, &mut inval);
+    }
+    output.prev_inodes = HashMap::new();
+    Ok(output)
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn file(path: &str) -> OutputEntry {
+    OutputEntry::file(path, OutputFile::new(OutputFileMetadata::new(0), |_, _| Ok(Vec::new())))
+  }
+
+  fn conflicts(paths: &[&str]) -> Vec<String> {
+    let entries: Vec<_> = paths.iter().map(|p| file(p)).collect();
+    validate(&entries).iter().map(|c| c.to_string()).collect()
+  }
+
+  fn output(paths: &[&str]) -> Output {
+    let mut output = Output::new();
+    output.insert_control_dir();
+    let mut inval = Invalidation::default();
+    for p in paths {
+      assert!(output.insert(file(p), &mut inval));
+    }
+    output
+  }
+
+  #[test]
+  fn validate_paths() {
+    assert!(conflicts(&["a", "b/c", "b/d/e"]).is_empty());
+    assert_eq!(conflicts(&["a/b", "a/./b"]), ["duplicate file path: \"a/./b\""]);
+    assert_eq!(conflicts(&["a", "a/b"]), ["path used as both file and dir: \"a\""]);
+    assert_eq!(conflicts(&["/a", "a/b"]), ["absolute path: \"/a\"", "path used as both file and dir: \"/a\""]);
+    assert_eq!(conflicts(&["/a"]), ["absolute path: \"/a\""]);
+    assert_eq!(conflicts(&["a//b"]), ["empty path component: \"a//b\""]);
+    assert_eq!(conflicts(&[""]), ["empty path component: \"\""]);
+    assert_eq!(conflicts(&["", "a"]), ["empty path component: \"\""]);
+    assert_eq!(conflicts(&["a/../b"]), ["'..' path component: \"a/../b\""]);
+    assert_eq!(conflicts(&[".transformfs/error"]), ["reserved path: \".transformfs/error\""]);
+  }
+
+  #[test]
+  fn insert_and_remove() {
+    let mut output = output(&["a/b/c", "a/d"]);
+    let mut inval = Invalidation::default();
+    assert!(!output.insert(file("a/b"), &mut inval));
+    assert!(!output.insert(file("a/d/e"), &mut inval));
+    assert!(!output.insert(file("../x"), &mut inval));
+    assert!(!output.insert(file("x//y"), &mut inval));
+    assert!(!output.insert(file(".transformfs/stats"), &mut inval));
+    assert!(!output.remove(OsStr::new(".transformfs/stats"), &mut inval));
+    assert!(output.lookup(OsStr::new("/.transformfs/stats")).is_some());
+
+    // empty parent dirs are removed with the file
+    assert!(output.remove(OsStr::new("a/b/c"), &mut inval));
+    assert!(output.lookup(OsStr::new("/a/b")).is_none());
+    assert!(output.lookup(OsStr::new("/a/d")).is_some());
+  }
+
+  #[test]
+  fn reuse_inodes() {
+    let config = Config::default();
+    let ino = |output: &Output, path: &str| output.lookup(OsStr::new(path)).map(|(ino, _)| ino);
+    let first = Output::init(vec![file("a/b"), file("c"), file("d")], &config, None).unwrap();
+    let second = Output::init(vec![file("a/b"), file("c/e"), file("f")], &config, Some(&first)).unwrap();
+    for path in ["", "a", "a/b"] {
+      assert_eq!(ino(&first, path), ino(&second, path));
+    }
+    // kind changed from file to dir
+    assert_ne!(ino(&first, "c"), ino(&second, "c"));
+    assert_eq!(ino(&second, "d"), None);
+    // removed inodes are not reused
+    let old: HashSet<_> = first.inode_map.keys().collect();
+    for path in ["c", "c/e", "f"] {
+      assert!(!old.contains(&ino(&second, path).unwrap()));
+    }
+  }
+
+  #[test]
+  fn keep_control_inodes() {
+    let config = Config { control_dir: true, ..Config::default() };
+    let first = Output::init(vec![file("a")], &config, None).unwrap();
+    let second = Output::init(vec![file("b")], &config, Some(&first)).unwrap();
+    for path in ["/.transformfs", "/.transformfs/status", "/.transformfs/stats"] {
+      let path = OsString::from(path);
+      assert_eq!(first.lookup_path(&path).unwrap().0, second.lookup_path(&path).unwrap().0);
+    }
+  }
+
+  #[test]
+  fn replace_open_inodes() {
+    let config = Config::default();
+    let ino = |output: &Output, path: &str| output.lookup_path(&OsString::from(path)).unwrap().0;
+    let mut first = Output::init(vec![file("a"), file("b")], &config, None).unwrap();
+    first.set_open_inodes([ino(&first, "/a")].into());
+    let mut second = Output::init(vec![file("a"), file("b")], &config, Some(&first)).unwrap();
+    assert_ne!(ino(&first, "/a"), ino(&second, "/a"));
+    assert_eq!(ino(&first, "/b"), ino(&second, "/b"));
+
+    // incremental updates
+    let open = ino(&second, "/b");
+    second.set_open_inodes([open].into());
+    let mut inval = Invalidation::default();
+    second.insert(file("b"), &mut inval);
+    assert_ne!(ino(&second, "/b"), open);
+    assert!(!second.inode_map.contains_key(&open));
+    let Some((_, OutputEntry { content: OutputContent::Dir(entries), .. })) = second.lookup_path(&OsString::from("/")) else {
+      panic!("root is not a dir");
+    };
+    assert_eq!(entries.len(), 2);
+  }
+
+  #[test]
+  fn validate_patches() {
+    let mut output = output(&["a/b", "c"]);
+    output.insert(OutputEntry {
+      path: ERROR_FILE.into(),
+      content: OutputContent::Control(ControlFile::TransformError)
+    }, &mut Invalidation::default());
+    let check = |add: &[&str], remove: &[&str]| {
+      let patch = OutputPatch {
+        add: add.iter().map(|p| file(p)).collect(),
+        remove: remove.iter().map(OsString::from).collect()
+      };
+      output.validate_patch(&patch).iter().map(|c| c.to_string()).collect::<Vec<_>>()
+    };
+    assert!(check(&["a/c", "c"], &[]).is_empty());
+    assert_eq!(check(&["a"], &[]), ["path used as both file and dir: \"a\""]);
+    assert!(check(&["a"], &["a/b"]).is_empty());
+    assert_eq!(check(&["c/d"], &[]), ["path used as both file and dir: \"c/d\""]);
+    assert!(check(&["c/d"], &["c"]).is_empty());
+    assert_eq!(check(&[".transformfs/stats"], &[]), ["reserved path: \".transformfs/stats\""]);
+    assert_eq!(check(&[ERROR_FILE], &[]), ["reserved path: \"TRANSFORM_ERROR\""]);
+  }
+}
@@ -0,0 +1,113 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use anyhow::Context;
+use log::info;
+use crate::{output::{OutputContent, OutputEntry}, transform::{Transform, TransformLoader}, utils::{Input, InputKind}};
+
+// outputs of a stage as inputs of the next stage
+fn outputs_to_inputs(outputs: Vec<OutputEntry>) -> Vec<Input> {
+  outputs.into_iter()
+    .filter_map(|e| match e.content {
+      OutputContent::File(f) => Some(Input {
+        path: e.path,
+        kind: InputKind::File,
+        root: None,
+        file: Some(f)
+      }),
+      OutputContent::Dir(_) | OutputContent::Control(_) => None
+    })
+    .collect()
+}
+
+/// Chain of transforms where outputs of each stage are inputs of the next one
+///
+/// Reads of the final outputs flow through read callbacks of all stages in-process
+pub struct Pipeline {
+  stages: Vec<Arc<dyn Transform>>
+}
+
+impl Transform for Pipeline {
+  fn transform(&self, inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+    let Some((first, rest)) = self.stages.split_first() else {
+      anyhow::bail!("Pipeline has no stage");
+    };
+    let mut outputs = first.transform(inputs).context("Pipeline stage 1 failed")?;
+    for (i, stage) in rest.iter().enumerate() {
+      info!("Pipeline stage {}: {} input(s)", i + 2, outputs.len());
+      outputs = stage.transform(&outputs_to_inputs(outputs))
+        .with_context(|| format!("Pipeline stage {} failed", i + 2))?;
+    }
+    Ok(outputs)
+  }
+}
+
+/// Load all stages of a pipeline (all of them are reloaded together)
+pub struct PipelineLoader {
+  pub stages: Vec<Box<dyn TransformLoader>>
+}
+
+impl TransformLoader for PipelineLoader {
+  fn name(&self) -> String {
+    self.stages.iter()
+      .map(|s| s.name())
+      .collect::<Vec<_>>()
+      .join(" | ")
+  }
+
+  fn load(&self) -> anyhow::Result<Arc<dyn Transform>> {
+    Ok(Arc::new(Pipeline {
+      stages: self.stages.iter()
+        .map(|s| s.load())
+        .collect::<anyhow::Result<_>>()?
+    }))
+  }
+}
+
+// examples use goto and io (not in Luau)
+#[cfg(all(test, not(feature = "luau")))]
+mod tests {
+  use std::{fs, io::Read, path::Path};
+  use crate::{builder::TransformFsBuilder, transformfs::Config};
+
+  #[test]
+  fn chain_scripts() {
+    let dir = std::env::temp_dir().join(format!("transformfs-pipeline-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("hello.txt"), "one\ntwo\n").unwrap();
+    fs::write(dir.join("other.txt"), "three\n").unwrap();
+
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut params = serde_json::Map::new();
+    params.insert("include".into(), "hello".into());
+    params.insert("exclude".into(), "other".into());
+    let mut fs = TransformFsBuilder::new()
+      .script(examples.join("filter.lua"))
+      .script(examples.join("line_number.lua"))
+      .inputs([&dir])
+      .config(Config { params, ..Config::default() })
+      .build()
+      .unwrap();
+
+    assert!(fs.output().lookup(dir.join("other.txt.txt").as_os_str()).is_none());
+    let (ino, _) = fs.output().lookup(dir.join("hello.txt.txt").as_os_str()).unwrap();
+    let mut data = String::new();
+    fs.reader(ino, 2, u64::MAX, 4).unwrap().read_to_string(&mut data).unwrap();
+    assert_eq!(data, "one\n2 two");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,296 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{ffi::OsString, fs, io, os::unix::ffi::OsStringExt, path::{Path, PathBuf}};
+use landlock::{Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, ABI};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use mlua::{Function, IntoLuaMulti, Lua, MultiValue, String as LuaString, Table};
+
+use crate::lua::MODULE_PATTERNS;
+
+/// Functions removed from sandboxed scripts
+const REMOVED_OS_FNS: [&str; 5] = ["execute", "exit", "remove", "rename", "tmpname"];
+const REMOVED_IO_FNS: [&str; 3] = ["popen", "input", "output"];
+const REMOVED_GLOBALS: [&str; 2] = ["dofile", "loadfile"];
+/// Functions loading chunks which are limited to text (crafted bytecode can escape the sandbox)
+#[cfg(not(feature = "luau"))]
+const TEXT_ONLY_LOADERS: [&str; 2] = ["load", "loadstring"];
+
+/// Field of package with the module searchers and index of the Lua file searcher in it
+#[cfg(feature = "lua54")]
+const SEARCHERS: (&str, usize) = ("searchers", 2);
+#[cfg(feature = "luajit")]
+const SEARCHERS: (&str, usize) = ("loaders", 2);
+#[cfg(feature = "luau")]
+const SEARCHERS: (&str, usize) = ("loaders", 1);
+
+// canonical path (or canonical parent for files not created yet)
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+  match path.canonicalize() {
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {
+      let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(err);
+      };
+      let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+      Ok(parent.canonicalize()?.join(name))
+    },
+    result => result
+  }
+}
+
+fn lua_path(s: &LuaString) -> PathBuf {
+  PathBuf::from(OsString::from_vec(s.as_bytes().to_vec()))
+}
+
+// searcher of Lua modules only under fixed dirs
+// (unlike the default one, it ignores package.path which scripts can change)
+fn module_searcher(lua: &Lua, dirs: &[&Path]) -> mlua::Result<Function> {
+  let dirs: Vec<PathBuf> = dirs.iter().filter_map(|d| d.canonicalize().ok()).collect();
+  lua.create_function(move |lua, name: String| {
+    let name = name.replace('.', "/");
+    let mut tried = Vec::new();
+    for dir in &dirs {
+      for pattern in MODULE_PATTERNS {
+        let path = dir.join(pattern.replace('?', &name));
+        match path.canonicalize() {
+          // module names with .. can't escape the dir
+          Ok(p) if p.starts_with(dir) && p.is_file() => {
+            let source = fs::read(&p).map_err(mlua::Error::external)?;
+            let chunk = lua.load(source)
+              .set_name(format!("@{}", p.display()))
+              .into_function()?;
+            return (chunk, p.display().to_string()).into_lua_multi(lua);
+          },
+          _ => tried.push(format!("no file '{}'", path.display()))
+        }
+      }
+    }
+    // only LuaJIT doesn't separate messages of searchers
+    let sep = if cfg!(feature = "luajit") { "\n\t" } else { "" };
+    format!("{}{}", sep, tried.join("\n\t")).into_lua_multi(lua)
+  })
+}
+
+/// Paths accessible to sandboxed scripts
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sandbox {
+  /// Readable roots (canonical)
+  read_roots: Vec<PathBuf>,
+  /// Readable and writable dir (canonical)
+  scratch_dir: Option<PathBuf>
+}
+
+impl Sandbox {
+  pub fn new(inputs: &[PathBuf], scratch_dir: Option<&Path>) -> anyhow::Result<Self> {
+    let read_roots = inputs.iter()
+      .filter_map(|p| match p.canonicalize() {
+        Ok(p) => Some(p),
+        Err(err) => {
+          warn!("Input {:?} not accessible in sandbox: {}", p, err);
+          None
+        }
+      })
+      .collect();
+    let scratch_dir = scratch_dir
+      .map(|d| d.canonicalize().map_err(|e| anyhow::anyhow!("Invalid scratch dir {:?}: {}", d, e)))
+      .transpose()?;
+    Ok(Self { read_roots, scratch_dir })
+  }
+
+  /// Check whether a path can be accessed
+  pub fn check(&self, path: &Path, write: bool) -> Result<(), String> {
+    let denied = || format!("{}: Permission denied (sandbox)", path.display());
+    let path = resolve(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let in_scratch = self.scratch_dir.as_ref().is_some_and(|d| path.starts_with(d));
+    let in_inputs = self.read_roots.iter().any(|r| path.starts_with(r));
+    if in_scratch || (!write && in_inputs) {
+      Ok(())
+    } else {
+      Err(denied())
+    }
+  }
+
+  /// Remove functions to run commands or modify files,
+  /// restrict io.open and io.lines to inputs and scratch dir,
+  /// and load only text chunks and modules from module dirs
+  pub fn apply(&self, lua: &Lua, module_dirs: &[&Path]) -> mlua::Result<()> {
+    let globals = lua.globals();
+    for name in REMOVED_GLOBALS {
+      globals.set(name, mlua::Value::Nil)?;
+    }
+    // Luau can't load bytecode from scripts
+    #[cfg(not(feature = "luau"))]
+    for name in TEXT_ONLY_LOADERS {
+      let Some(load) = globals.get::<_, Option<Function>>(name)? else {
+        continue;
+      };
+      // force mode (the third argument) to text and keep env only if given
+      globals.set(name, lua.create_function(move |lua, args: MultiValue| {
+        let mut args: Vec<_> = args.into_iter().collect();
+        if args.len() < 3 {
+          args.resize(3, mlua::Value::Nil);
+        }
+        args[2] = mlua::Value::String(lua.create_string("t")?);
+        load.call::<_, MultiValue>(args.into_iter().collect::<MultiValue>())
+      })?)?;
+    }
+    if let Some(os) = globals.get::<_, Option<Table>>("os")? {
+      for name in REMOVED_OS_FNS {
+        os.set(name, mlua::Value::Nil)?;
+      }
+    }
+    if let Some(package) = globals.get::<_, Option<Table>>("package")? {
+      package.set("loadlib", mlua::Value::Nil)?;
+      // replace the file searcher and drop the searchers of C modules after it
+      let (field, index) = SEARCHERS;
+      let searchers: Table = package.get(field)?;
+      for i in (index + 1..=searchers.raw_len()).rev() {
+        searchers.raw_set(i, mlua::Value::Nil)?;
+      }
+      searchers.raw_set(index, module_searcher(lua, module_dirs)?)?;
+    }
+    // Luau has no io library
+    let Some(io) = globals.get::<_, Option<Table>>("io")? else {
+      return Ok(());
+    };
+    for name in REMOVED_IO_FNS {
+      io.set(name, mlua::Value::Nil)?;
+    }
+
+    // return nil and error message like io.open
+    let open: Function = io.get("open")?;
+    let sandbox = self.clone();
+    io.set("open", lua.create_function(move |lua, (path, mode): (LuaString, Option<LuaString>)| {
+      let write = mode.as_ref().is_some_and(|m| {
+        m.as_bytes().iter().any(|c| matches!(c, b'w' | b'a' | b'+'))
+      });
+      match sandbox.check(&lua_path(&path), write) {
+        Ok(()) => open.call::<_, MultiValue>((path, mode)),
+        Err(msg) => (mlua::Value::Nil, msg).into_lua_multi(lua)
+      }
+    })?)?;
+
+    let lines: Function = io.get("lines")?;
+    let sandbox = self.clone();
+    io.set("lines", lua.create_function(move |_, args: MultiValue| {
+      // without a file name it reads stdin
+      if let Some(mlua::Value::String(path)) = args.iter().next() {
+        sandbox.check(&lua_path(path), false).map_err(mlua::Error::runtime)?;
+      }
+      lines.call::<_, MultiValue>(args)
+    })?)?;
+    Ok(())
+  }
+}
+
+/// Restrict file access of the whole process with Landlock
+///
+/// It has no effect on kernels without Landlock support
+pub fn landlock(read: &[PathBuf], write: &[PathBuf]) -> anyhow::Result<()> {
+  let abi = ABI::V2;
+  let status = Ruleset::default()
+    .handle_access(AccessFs::from_all(abi))?
+    .create()?
+    .add_rules(landlock::path_beneath_rules(read, AccessFs::from_read(abi)))?
+    .add_rules(landlock::path_beneath_rules(write, AccessFs::from_all(abi)))?
+    .restrict_self()?;
+  match status.ruleset {
+    RulesetStatus::FullyEnforced => info!("Landlock fully enforced"),
+    RulesetStatus::PartiallyEnforced => warn!("Landlock partially enforced (old kernel)"),
+    RulesetStatus::NotEnforced => warn!("Landlock not enforced (not supported by kernel)")
+  };
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // fresh dir under the temporary dir
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("transformfs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  // sandboxed Lua state with inputs and scratch dir in globals
+  fn sandboxed(name: &str) -> Lua {
+    let root = temp_dir(name);
+    let (input, scratch) = (root.join("input"), root.join("scratch"));
+    fs::create_dir(&input).unwrap();
+    fs::create_dir(&scratch).unwrap();
+    fs::write(input.join("a.txt"), "a").unwrap();
+    fs::write(root.join("secret"), "s").unwrap();
+
+    let lua = Lua::new();
+    Sandbox::new(std::slice::from_ref(&input), Some(&scratch)).unwrap().apply(&lua, &[]).unwrap();
+    let globals = lua.globals();
+    for (name, path) in [("root", &root), ("input", &input), ("scratch", &scratch)] {
+      globals.set(name, path.to_str().unwrap()).unwrap();
+    }
+    lua
+  }
+
+  #[test]
+  fn remove_functions() {
+    let lua = sandboxed("remove");
+    let removed: bool = lua.load(r#"
+      return os.execute == nil and os.remove == nil and dofile == nil and loadfile == nil
+        and (io == nil or (io.popen == nil and io.output == nil))
+    "#).eval().unwrap();
+    assert!(removed);
+  }
+
+  #[cfg(not(feature = "luau"))]
+  #[test]
+  fn restrict_files() {
+    let lua = sandboxed("files");
+    let result: String = lua.load(r#"
+      local f = assert(io.open(input .. "/a.txt"))
+      local content = f:read("a")
+      f:close()
+      local secret, err = io.open(root .. "/secret")
+      local escaped = io.open(input .. "/../secret")
+      local written = io.open(input .. "/b.txt", "w")
+      local scratch_file = assert(io.open(scratch .. "/b.txt", "w"))
+      scratch_file:close()
+      local lines_ok = pcall(io.lines, root .. "/secret")
+      return table.concat({
+        content, tostring(secret), tostring(escaped), tostring(written), tostring(lines_ok),
+        tostring(err:find("Permission denied", 1, true) ~= nil)
+      }, " ")
+    "#).eval().unwrap();
+    assert_eq!(result, "a nil nil nil false true");
+  }
+
+  #[cfg(not(feature = "luau"))]
+  #[test]
+  fn reject_bytecode() {
+    let lua = sandboxed("bytecode");
+    let result: String = lua.load(r#"
+      local bytecode = string.dump(function() return 42 end)
+      local f, err = load(bytecode)
+      local g = loadstring and loadstring(bytecode)
+      local reader = load(coroutine.wrap(function() coroutine.yield(bytecode) end))
+      return table.concat({
+        tostring(f), tostring(err ~= nil), tostring(g), tostring(reader),
+        load("return 1 + 1")(), load("return x", "=c", "t", { x = 5 })()
+      }, " ")
+    "#).eval().unwrap();
+    assert_eq!(result, "nil true nil nil 2 5");
+  }
+}
@@ -0,0 +1,79 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{fmt, sync::Arc, time::Duration};
+use crate::{output::{OutputEntry, OutputPatch}, utils::Input};
+
+/// Transform inputs to output files
+///
+/// Implemented by Lua scripts (LuaTransform) or natively in Rust
+pub trait Transform: Send + Sync {
+  /// Transform all inputs to outputs
+  fn transform(&self, inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>>;
+
+  /// Whether on_change is implemented
+  /// (updates are skipped when no input has changed)
+  fn incremental(&self) -> bool {
+    false
+  }
+
+  /// Incremental alternative to transform when inputs change
+  ///
+  /// Return None to rerun transform on all inputs
+  fn on_change(&self, _added: &[Input], _removed: &[Input], _modified: &[Input]) -> anyhow::Result<Option<OutputPatch>> {
+    Ok(None)
+  }
+}
+
+/// Error of a callback aborted by a resource limit
+#[derive(Debug)]
+pub enum LimitExceeded {
+  /// Call took longer than the time limit
+  Time(Duration),
+  /// Heap of the transform exceeded the memory limit
+  Memory
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LimitExceeded::Time(limit) => write!(f, "call exceeded time limit of {:?}", limit),
+      LimitExceeded::Memory => write!(f, "memory limit exceeded")
+    }
+  }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Create the transform of TransformFs (called again on reload)
+pub trait TransformLoader: Send {
+  /// Name shown in logs and status
+  fn name(&self) -> String;
+
+  fn load(&self) -> anyhow::Result<Arc<dyn Transform>>;
+}
+
+/// Loader of a transform created in advance (reused on reload)
+pub struct StaticLoader(pub Arc<dyn Transform>);
+
+impl TransformLoader for StaticLoader {
+  fn name(&self) -> String {
+    "native".to_string()
+  }
+
+  fn load(&self) -> anyhow::Result<Arc<dyn Transform>> {
+    Ok(self.0.clone())
+  }
+}
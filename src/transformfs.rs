@@ -13,75 +13,202 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use log::{error, info};
-use fuser::{Filesystem, Request};
-use mlua::{FromLua, Function, Lua, String as LuaString, Table};
-use std::{ffi::OsStr, fs, path::{Path, PathBuf}, time::{Duration, SystemTime}
+use log::{error, info, warn};
+use fuser::{consts::FOPEN_DIRECT_IO, Filesystem, Notifier, Request, TimeOrNow};
+use std::{collections::HashMap, ffi::{OsStr, OsString}, io::{self, Read}, path::{Path, PathBuf}, sync::{mpsc::Sender, Arc, Mutex, MutexGuard, PoisonError}, time::{Duration, SystemTime, UNIX_EPOCH}
 };
-use nix::errno::Errno::{EIO, ENOENT};
-use crate::output::{Output, OutputContent, OutputEntry};
+use nix::{errno::Errno::{self, EACCES, EBADF, EIO, ENOENT, EROFS, ETIMEDOUT}, libc::{O_ACCMODE, O_RDONLY}};
+use crate::{builder::TransformFsBuilder, output::{report_conflicts, ControlFile, Invalidation, Output, OutputContent, OutputEntry, OutputFile, OutputFileMetadata, ERROR_FILE}, transform::{LimitExceeded, Transform, TransformLoader}, utils::{self, Input, InputSnapshot}, worker::WorkerCrashed};
 
-fn load_fn(table: &Table, name: &str) -> mlua::Result<Option<Function>> {
-  Ok(
-    if table.contains_key(name)? {
-      Some(table.get::<_, Function>(name)?)
-    } else {
-      None
+/// What to do when transform fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+  /// Fail to mount if the first transform fails
+  Abort,
+  /// Mount anyway and retry failed updates with backoff
+  Retry
+}
+
+/// User parameters passed to the script
+pub type Params = serde_json::Map<String, serde_json::Value>;
+
+pub struct Config {
+  pub timeout: Duration,
+  /// Pass all walk entries (including dirs and special files) to transform
+  pub all_entries: bool,
+  /// Add the control dir to output
+  pub control_dir: bool,
+  /// Failure policy of transform
+  pub on_error: OnError,
+  /// Initial interval to retry a failed update (doubled on each failure)
+  pub retry_interval: Duration,
+  /// Maximum interval to retry a failed update
+  pub max_retry_interval: Duration,
+  /// Add an error file to the root of output when an update fails
+  pub error_file: bool,
+  /// Fail if output paths are invalid or conflict
+  pub strict: bool,
+  /// Parameters passed to setup and transform of scripts
+  pub params: Params,
+  /// Extra dirs to search for Lua modules
+  pub lua_path: Vec<PathBuf>,
+  /// Restrict scripts to read only inputs and write only the scratch dir
+  pub sandbox: bool,
+  /// Dir scripts can read and write in sandbox mode
+  pub scratch_dir: Option<PathBuf>,
+  /// Restrict file access of the process with Landlock after mounting
+  pub landlock: bool,
+  /// Time limit of each call into scripts
+  pub lua_time_limit: Option<Duration>,
+  /// Maximum heap size of each script in bytes
+  pub lua_memory_limit: Option<usize>,
+  /// Run each script in a worker process restarted on crash (conflicts with landlock)
+  pub isolate: bool,
+  /// Timeout of commands run for outputs
+  pub command_timeout: Option<Duration>
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      timeout: Duration::MAX,
+      all_entries: false,
+      control_dir: false,
+      on_error: OnError::Abort,
+      retry_interval: Duration::from_secs(1),
+      max_retry_interval: Duration::from_secs(300),
+      error_file: false,
+      strict: false,
+      params: Params::new(),
+      lua_path: Vec::new(),
+      sandbox: false,
+      scratch_dir: None,
+      landlock: false,
+      lua_time_limit: None,
+      lua_memory_limit: None,
+      isolate: false,
+      command_timeout: Some(Duration::from_secs(60))
     }
-  )
+  }
 }
 
-struct UserFn {
-  transform: Function
+// errno returned when a callback of an output file fails
+fn callback_errno(err: &anyhow::Error) -> Errno {
+  match err.downcast_ref::<LimitExceeded>() {
+    Some(LimitExceeded::Time(_)) => ETIMEDOUT,
+    _ => EIO
+  }
 }
 
-impl FromLua for UserFn {
-  fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
-    let mlua::Value::Table(table) = &value else {
-      return Err(mlua::Error::runtime("User script must export a Lua table"));
-    };
-    Ok(UserFn {
-      transform: load_fn(table, "transform")?.ok_or(
-        mlua::Error::runtime("transform not defined in user module")
-      )?
-    })
+/// Counters shown in the control dir
+#[derive(Default)]
+struct Stats {
+  updates: u64,
+  failed_updates: u64,
+  opens: u64,
+  reads: u64,
+  bytes_read: u64,
+  errors: u64
+}
+
+/// File opened by a handle
+struct OpenFile {
+  ino: u64,
+  path: OsString,
+  /// Kept until release even if output is refreshed or transform is reloaded
+  file: Arc<OutputFile>,
+  /// Control file rendered on open (not counted in stats)
+  control: bool
+}
+
+// run transform and build output (with the control dir if enabled)
+fn build_output(transform: &dyn Transform, inputs: &[Input], config: &Config, prev: Option<&Output>) -> anyhow::Result<Output> {
+  Output::init(transform.transform(inputs)?, config, prev)
+}
+
+// states of inputs (only needed to find changes for incremental transforms)
+fn input_snapshot(transform: &dyn Transform, inputs: &[Input]) -> InputSnapshot {
+  if transform.incremental() {
+    utils::snapshot(inputs)
+  } else {
+    InputSnapshot::new()
   }
 }
 
-pub struct Config {
-  pub timeout: Duration,
+fn unix_secs(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// part of data to return for a read
+fn slice_data(data: &[u8], offset: i64, size: u32) -> &[u8] {
+  let start = (offset as usize).min(data.len());
+  let end = start.saturating_add(size as usize).min(data.len());
+  &data[start..end]
 }
 
 pub struct TransformFs {
   inputs: Vec<PathBuf>,
+  /// Create transform on load and reload
+  loader: Box<dyn TransformLoader>,
   config: Config,
 
-  /// Lua state with loaded script
-  lua: Lua,
-  /// Loaded user-defined function
-  user_fn: UserFn,
+  /// Loaded transform (None if it has never loaded)
+  transform: Option<Arc<dyn Transform>>,
   /// Last updated time
   last_updated: SystemTime,
+  /// Inputs states at last update (empty unless transform is incremental)
+  input_snapshot: InputSnapshot,
 
   /// Output
   output: Output,
+  /// Map file handle to opened file
+  open_files: HashMap<u64, OpenFile>,
+  /// Next file handle to allocate
+  next_fh: u64,
+
+  /// Last error of updates or callbacks
+  last_error: Option<String>,
+  /// Number of consecutive failed updates
+  failures: u32,
+  /// Time of the last failed update
+  last_failure: SystemTime,
+  /// Last reload failed so retries should load the transform again
+  pending_reload: bool,
+  stats: Stats,
+  /// Ask a background thread to reload script
+  reload_trigger: Option<Sender<()>>,
 
   default_attr: fuser::FileAttr
 }
 
 impl TransformFs {
-  pub fn init(inputs: Vec<PathBuf>, script: PathBuf, config: Config) -> anyhow::Result<Self> {
-    let lua = Lua::new();
-    let user_fn: UserFn = lua.load(fs::read_to_string(script)?).eval()?;
-    let output = Output::init(&lua, &user_fn.transform, &inputs)?;
+  pub fn builder() -> TransformFsBuilder {
+    TransformFsBuilder::new()
+  }
+
+  pub fn init(inputs: Vec<PathBuf>, loader: Box<dyn TransformLoader>, config: Config) -> anyhow::Result<Self> {
     let cur_time = SystemTime::now();
-    Ok(Self {
+    let mut output = Output::new();
+    if config.control_dir {
+      output.insert_control_dir();
+    }
+    let mut fs = Self {
       inputs,
+      loader,
       config,
-      lua,
-      user_fn,
+      transform: None,
       last_updated: cur_time,
+      input_snapshot: InputSnapshot::new(),
       output,
+      open_files: HashMap::new(),
+      next_fh: 0,
+      last_error: None,
+      failures: 0,
+      last_failure: cur_time,
+      pending_reload: false,
+      stats: Stats::default(),
+      reload_trigger: None,
       default_attr: fuser::FileAttr {
         // must be overwritten
         ino: 0,
@@ -102,11 +229,39 @@ impl TransformFs {
         rdev: 0,
         flags: 0
       }
-    })
+    };
+
+    match fs.load() {
+      Err(err) if fs.config.on_error == OnError::Abort => return Err(err),
+      result => {
+        fs.finish_update(result);
+      }
+    };
+    Ok(fs)
   }
 
   /// Update output when timeout
+  ///
+  /// After a failed update, wait for the retry backoff instead
   pub fn update(&mut self) {
+    if self.failures > 0 {
+      match self.retry_delay() {
+        Some(delay) if delay.is_zero() => {
+          let result = self.retry();
+          self.finish_update(result);
+        },
+        Some(_) => {},
+        // not retrying: rerun on timeout after the last failure
+        None => {
+          if self.last_failure.elapsed().is_ok_and(|e| e > self.config.timeout) {
+            let result = self.retry();
+            self.finish_update(result);
+          }
+        }
+      };
+      return;
+    }
+
     match self.last_updated.elapsed() {
       Ok(elapsed) => {
         if elapsed <= self.config.timeout {
@@ -120,38 +275,414 @@ impl TransformFs {
     };
 
     info!("Update output on timeout");
-    match Output::init(&self.lua, &self.user_fn.transform, &self.inputs) {
-      Ok(output) => self.output = output,
-      Err(err) => error!("{}", err)
+    let result = self.refresh();
+    self.finish_update(result);
+  }
+
+  /// Record the result of an update and add or remove the error file
+  ///
+  /// Return the kernel cache entries to invalidate
+  pub fn finish_update(&mut self, result: anyhow::Result<Invalidation>) -> Invalidation {
+    let error_file = OsString::from(ERROR_FILE);
+    match result {
+      Ok(mut inval) => {
+        self.stats.updates += 1;
+        self.failures = 0;
+        // output may be patched in place so the error file can still exist
+        let path = Path::new("/").join(ERROR_FILE).into_os_string();
+        let has_error_file = matches!(
+          self.output.lookup_path(&path),
+          Some((_, OutputEntry { content: OutputContent::Control(ControlFile::TransformError), .. }))
+        );
+        if has_error_file {
+          self.output.remove_control(&error_file, &mut inval);
+        }
+        inval
+      },
+      Err(err) => {
+        self.stats.failed_updates += 1;
+        self.failures += 1;
+        self.last_failure = SystemTime::now();
+        // alternate format to include the full error chain and Lua traceback
+        self.report_error(format!("{:#}", err));
+        let mut inval = Invalidation::default();
+        if self.config.error_file {
+          let path = Path::new("/").join(ERROR_FILE).into_os_string();
+          match self.output.lookup_path(&path) {
+            Some((_, OutputEntry { content: OutputContent::Control(_), .. })) | None => {
+              self.output.insert(OutputEntry {
+                path: error_file,
+                content: OutputContent::Control(ControlFile::TransformError)
+              }, &mut inval);
+            },
+            // never replace an output of the transform
+            Some(_) => warn!("Not adding {}: path used by an output", ERROR_FILE)
+          };
+        }
+        inval
+      }
+    }
+  }
+
+  /// Time to wait before retrying the failed update
+  ///
+  /// Return None if there is nothing to retry
+  pub fn retry_delay(&self) -> Option<Duration> {
+    if self.failures == 0 || self.config.on_error != OnError::Retry {
+      return None;
+    }
+    let backoff = self.config.retry_interval
+      .saturating_mul(2u32.saturating_pow(self.failures - 1))
+      .min(self.config.max_retry_interval);
+    Some(
+      (self.last_failure + backoff).duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+    )
+  }
+
+  /// Retry the failed update
+  pub fn retry(&mut self) -> anyhow::Result<Invalidation> {
+    info!("Retry failed update (attempt {})", self.failures + 1);
+    if self.pending_reload {
+      // the new script failed to load so retry loading it
+      return self.load();
+    }
+    self.refresh()
+  }
+
+  // log error and keep it for the control dir
+  fn report_error(&mut self, err: String) {
+    error!("{}", err);
+    self.stats.errors += 1;
+    self.last_error = Some(err);
+  }
+
+  // report a failed callback of an output file and return its errno
+  fn callback_failed(&mut self, msg: String, err: &anyhow::Error) -> Errno {
+    self.report_error(msg);
+    if err.is::<WorkerCrashed>() {
+      // outputs of the crashed worker are stale
+      if let Some(trigger) = &self.reload_trigger {
+        let _ = trigger.send(());
+      }
+    }
+    callback_errno(err)
+  }
+
+  /// Reload script in background when writing to the control file
+  pub fn set_reload_trigger(&mut self, trigger: Sender<()>) {
+    self.reload_trigger = Some(trigger);
+  }
+
+  /// Drop the reload trigger (ending the thread waiting on it)
+  pub fn remove_reload_trigger(&mut self) {
+    self.reload_trigger = None;
+  }
+
+  fn alloc_fh(&mut self) -> u64 {
+    self.next_fh += 1;
+    self.next_fh - 1
+  }
+
+  /// Rerun transform (or on_change if defined) and update output
+  ///
+  /// Return the kernel cache entries to invalidate on success
+  pub fn refresh(&mut self) -> anyhow::Result<Invalidation> {
+    let Some(transform) = self.transform.clone() else {
+      // transform failed to load before
+      return self.load();
     };
+    let inputs = utils::read_inputs(&self.inputs, self.config.all_entries);
+    let snapshot = input_snapshot(&*transform, &inputs);
+    self.mark_open_inodes();
+
+    if transform.incremental() {
+      let changes = utils::diff_inputs(&self.input_snapshot, &inputs, &snapshot);
+      if changes.is_empty() {
+        self.last_updated = SystemTime::now();
+        return Ok(Invalidation::default());
+      }
+      info!(
+        "Inputs changed: {} added, {} removed, {} modified",
+        changes.added.len(),
+        changes.removed.len(),
+        changes.modified.len()
+      );
+      let patch = transform.on_change(&changes.added, &changes.removed, &changes.modified)?;
+      // fall back to full transform if nil is returned
+      if let Some(patch) = patch {
+        report_conflicts(self.output.validate_patch(&patch), &self.config)?;
+        let inval = self.output.patch(patch);
+        self.input_snapshot = snapshot;
+        self.last_updated = SystemTime::now();
+        return Ok(inval);
+      }
+    }
+
+    let output = build_output(&*transform, &inputs, &self.config, Some(&self.output))?;
+    self.input_snapshot = snapshot;
+    self.last_updated = SystemTime::now();
+    Ok(std::mem::replace(&mut self.output, output).invalidate_all())
+  }
+
+  /// Reload transform (e.g. user script in a fresh Lua state) and rebuild output
+  ///
+  /// The previous state is kept if the new transform fails
+  pub fn reload(&mut self) -> anyhow::Result<Invalidation> {
+    info!("Reload transform {}", self.loader.name());
+    let result = self.load();
+    self.pending_reload = result.is_err();
+    result
+  }
+
+  // load transform and build output
+  fn load(&mut self) -> anyhow::Result<Invalidation> {
+    let transform = self.loader.load()?;
+    let inputs = utils::read_inputs(&self.inputs, self.config.all_entries);
+    let snapshot = input_snapshot(&*transform, &inputs);
+    self.mark_open_inodes();
+    let output = build_output(&*transform, &inputs, &self.config, Some(&self.output))?;
+
+    let inval = std::mem::replace(&mut self.output, output).invalidate_all();
+    self.transform = Some(transform);
+    self.input_snapshot = snapshot;
+    self.last_updated = SystemTime::now();
+    self.pending_reload = false;
+    Ok(inval)
+  }
+
+  // files with open handles get new inodes if replaced
+  // as the kernel caches pages by inode (open handles keep the old content)
+  fn mark_open_inodes(&mut self) {
+    let inodes = self.open_files.values()
+      .filter(|f| !f.control)
+      .map(|f| f.ino)
+      .collect();
+    self.output.set_open_inodes(inodes);
+  }
+
+  pub fn output(&self) -> &Output {
+    &self.output
   }
 
   pub fn read_metadata(&self, ino: u64, entry: &OutputEntry) -> anyhow::Result<fuser::FileAttr> {
     Ok(match &entry.content {
-      OutputContent::File(f) => {
-        let size = f.metadata.size;
-        let blksize = f.metadata.block_size.unwrap_or(self.default_attr.blksize);
+      OutputContent::File(f) => self.file_attr(ino, f),
+      OutputContent::Dir(_) => {
+        // TODO: calculate size
         fuser::FileAttr {
           ino,
-          kind: fuser::FileType::RegularFile,
-          size,
-          blksize,
-          perm: 0o644,
-          blocks: (size + blksize as u64 - 1) / blksize as u64,
+          kind: fuser::FileType::Directory,
+          perm: 0o755,
           ..self.default_attr
         }
       },
-      OutputContent::Dir(_) => {
-        // TODO: calculate size
+      OutputContent::Control(c) => {
+        let size = self.render_control(*c).len() as u64;
+        let blksize = self.default_attr.blksize;
         fuser::FileAttr {
           ino,
-          kind: fuser::FileType::Directory,
-          perm: 0o755,
+          kind: fuser::FileType::RegularFile,
+          size,
+          perm: if c.writable() { 0o644 } else { 0o444 },
+          blocks: size.div_ceil(blksize as u64),
           ..self.default_attr
         }
       }
     })
   }
+
+  fn file_attr(&self, ino: u64, f: &OutputFile) -> fuser::FileAttr {
+    let size = f.size();
+    let blksize = f.metadata.block_size.unwrap_or(self.default_attr.blksize);
+    let mtime = f.metadata.mtime.map_or(self.default_attr.mtime, |t| UNIX_EPOCH + Duration::from_secs(t));
+    fuser::FileAttr {
+      ino,
+      kind: fuser::FileType::RegularFile,
+      size,
+      blksize,
+      perm: 0o644,
+      blocks: size.div_ceil(blksize as u64),
+      mtime,
+      ctime: mtime,
+      ..self.default_attr
+    }
+  }
+
+  /// Attributes of a replaced file that still has open handles
+  fn open_file_attr(&self, ino: u64) -> Option<(fuser::FileAttr, Duration)> {
+    self.open_files.values()
+      .find(|f| f.ino == ino && !f.control)
+      .map(|f| (self.file_attr(ino, &f.file), self.file_ttl(&f.file)))
+  }
+
+  fn file_ttl(&self, f: &OutputFile) -> Duration {
+    // size of dynamic files changes without the output changing
+    if f.dynamic_size.is_some() {
+      Duration::ZERO
+    } else {
+      self.config.timeout
+    }
+  }
+
+  /// How long the kernel may cache attributes of an entry
+  fn attr_ttl(&self, entry: &OutputEntry) -> Duration {
+    match &entry.content {
+      OutputContent::File(f) => self.file_ttl(f),
+      OutputContent::Dir(_) => self.config.timeout,
+      OutputContent::Control(_) => Duration::ZERO
+    }
+  }
+
+  /// Open a file and return the file handle and open flags
+  pub fn open_file(&mut self, ino: u64, flags: i32) -> Result<(u64, u32), Errno> {
+    let Some(entry) = self.output.inode_map.get(&ino) else {
+      return Err(ENOENT);
+    };
+    let write = flags & O_ACCMODE != O_RDONLY;
+    let path = entry.path.clone();
+
+    match &entry.content {
+      OutputContent::File(f) => {
+        if write {
+          return Err(EROFS);
+        }
+        let f = f.clone();
+        if let Some(open) = &f.open {
+          if let Err(err) = open() {
+            return Err(self.callback_failed(format!("Error opening file {:?}: {:#}", path, err), &err));
+          }
+        }
+        self.stats.opens += 1;
+        let fh = self.alloc_fh();
+        let flags = if f.dynamic_size.is_some() { FOPEN_DIRECT_IO } else { 0 };
+        self.open_files.insert(fh, OpenFile {
+          ino,
+          path,
+          file: f,
+          control: false
+        });
+        Ok((fh, flags))
+      },
+      OutputContent::Control(c) => {
+        if write && !c.writable() {
+          return Err(EACCES);
+        }
+        // snapshot of the content when opened (kept even if the file is removed by an update)
+        let data = self.render_control(*c);
+        let file = OutputFile::new(OutputFileMetadata::new(data.len() as u64), move |offset, size| {
+          Ok(slice_data(&data, offset as i64, size).to_vec())
+        });
+        let fh = self.alloc_fh();
+        self.open_files.insert(fh, OpenFile {
+          ino,
+          path,
+          file: Arc::new(file),
+          control: true
+        });
+        // content changes between opens so bypass page cache
+        Ok((fh, FOPEN_DIRECT_IO))
+      },
+      OutputContent::Dir(_) => {
+        error!("Trying to open a dir {:?}", path);
+        Err(EIO)
+      }
+    }
+  }
+
+  /// Read data of an opened file
+  pub fn read_file(&mut self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, Errno> {
+    let Some(OpenFile { path, file, control, .. }) = self.open_files.get(&fh) else {
+      return Err(EBADF);
+    };
+
+    let result = (file.read)(offset as u64, size);
+    let (path, control) = (path.clone(), *control);
+    match result {
+      Ok(data) => {
+        if !control {
+          self.stats.reads += 1;
+          self.stats.bytes_read += data.len() as u64;
+        }
+        Ok(data)
+      },
+      Err(err) => {
+        Err(self.callback_failed(format!("Error reading file {:?}: {:#}", path, err), &err))
+      }
+    }
+  }
+
+  /// Close an opened file
+  pub fn release_file(&mut self, fh: u64) -> Result<(), Errno> {
+    let Some(OpenFile { path, file, .. }) = self.open_files.remove(&fh) else {
+      return Err(EBADF);
+    };
+
+    if let Some(close) = &file.close {
+      if let Err(err) = close() {
+        return Err(self.callback_failed(format!("Error closing file {:?}: {:#}", path, err), &err));
+      }
+    }
+    Ok(())
+  }
+
+  /// Open a file and read it from offset to end (or the size after opening) with Read
+  pub fn reader(&mut self, ino: u64, offset: u64, end: u64, chunk_size: u32) -> Result<FileReader<'_>, Errno> {
+    let (fh, _) = self.open_file(ino, O_RDONLY)?;
+    // size of files like command outputs is only known after opening
+    let size = self.open_files.get(&fh).map_or(end, |f| f.file.size());
+    Ok(FileReader {
+      fs: self,
+      fh,
+      offset,
+      end: end.min(size),
+      size,
+      chunk_size,
+      closed: false
+    })
+  }
+
+  // generate content of a control file
+  fn render_control(&self, file: ControlFile) -> Vec<u8> {
+    let text = match file {
+      ControlFile::Status => {
+        let files = self.output.inode_map.values()
+          .filter(|e| matches!(e.content, OutputContent::File(_)))
+          .count();
+        let dirs = self.output.inode_map.values()
+          .filter(|e| matches!(e.content, OutputContent::Dir(_)))
+          .count();
+        format!(
+          "state: {}\nscript: {}\ninputs: {}\nlast_updated: {}\nfiles: {}\ndirs: {}\nopen_files: {}\n",
+          if self.failures > 0 { "error" } else { "ok" },
+          self.loader.name(),
+          self.inputs.iter().map(|i| i.display().to_string()).collect::<Vec<_>>().join(" "),
+          unix_secs(self.last_updated),
+          files,
+          dirs,
+          self.open_files.len()
+        )
+      },
+      ControlFile::LastError | ControlFile::TransformError => {
+        self.last_error.as_ref().map(|e| format!("{}\n", e)).unwrap_or_default()
+      },
+      ControlFile::Stats => {
+        format!(
+          "updates: {}\nfailed_updates: {}\nopens: {}\nreads: {}\nbytes_read: {}\nerrors: {}\n",
+          self.stats.updates,
+          self.stats.failed_updates,
+          self.stats.opens,
+          self.stats.reads,
+          self.stats.bytes_read,
+          self.stats.errors
+        )
+      },
+      ControlFile::Outputs => {
+        serde_json::to_string_pretty(&self.output.to_json()).unwrap_or_default() + "\n"
+      },
+      ControlFile::Reload => String::new()
+    };
+    text.into_bytes()
+  }
 }
 
 impl Filesystem for TransformFs {
@@ -171,7 +702,7 @@ impl Filesystem for TransformFs {
 
     match self.read_metadata(ino, entry) {
       Ok(attr) => {
-        reply.entry(&self.config.timeout, &attr, 0);
+        reply.entry(&self.attr_ttl(entry), &attr, 0);
       },
       Err(err) => {
         error!("Error reading metadata of file {:?}: {}", entry.path, err);
@@ -184,13 +715,16 @@ impl Filesystem for TransformFs {
     self.update();
 
     let Some(entry) = self.output.inode_map.get(&ino) else {
-      reply.error(ENOENT as i32);
+      match self.open_file_attr(ino) {
+        Some((attr, ttl)) => reply.attr(&ttl, &attr),
+        None => reply.error(ENOENT as i32)
+      }
       return;
     };
 
     match self.read_metadata(ino, entry) {
       Ok(attr) => {
-        reply.attr(&self.config.timeout, &attr);
+        reply.attr(&self.attr_ttl(entry), &attr);
       },
       Err(err) => {
         error!("Error reading metadata of file {:?}: {}", entry.path, err);
@@ -199,62 +733,27 @@ impl Filesystem for TransformFs {
     };
   }
 
-  fn open(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-    let Some(entry) = self.output.inode_map.get(&ino) else {
-      reply.error(ENOENT as i32);
-      return;
+  fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+    match self.open_file(ino, flags) {
+      Ok((fh, open_flags)) => reply.opened(fh, open_flags),
+      Err(errno) => reply.error(errno as i32)
     };
-
-    match &entry.content {
-      OutputContent::File(f) => {
-        if let Some(open) = &f.open {
-          if let Err(err) = open.call::<_, ()>(()) {
-            error!("Error opening file {:?}: {}", entry.path, err);
-            reply.error(EIO as i32);
-            return;
-          }
-        }
-        // return dummy fh and flags as we only use ino in read
-        reply.opened(0, 0);
-      },
-      OutputContent::Dir(_) => {
-        error!("Trying to open a dir {:?}", entry.path);
-        reply.error(EIO as i32);
-      }
-    }
   }
 
   fn release(
     &mut self,
     _req: &Request<'_>,
-    ino: u64,
-    _fh: u64,
+    _ino: u64,
+    fh: u64,
     _flags: i32,
     _lock_owner: Option<u64>,
     _flush: bool,
     reply: fuser::ReplyEmpty,
   ) {
-    let Some(entry) = self.output.inode_map.get(&ino) else {
-      reply.error(ENOENT as i32);
-      return;
+    match self.release_file(fh) {
+      Ok(()) => reply.ok(),
+      Err(errno) => reply.error(errno as i32)
     };
-
-    match &entry.content {
-      OutputContent::File(f) => {
-        if let Some(close) = &f.close {
-          if let Err(err) = close.call::<_, ()>(()) {
-            error!("Error closing file {:?}: {}", entry.path, err);
-            reply.error(EIO as i32);
-            return;
-          }
-        }
-        reply.ok();
-      },
-      OutputContent::Dir(_) => {
-        error!("Calling close on a dir {:?}", entry.path);
-        reply.error(EIO as i32);
-      }
-    }
   }
 
   fn readdir(
@@ -285,7 +784,7 @@ impl Filesystem for TransformFs {
         }
         reply.ok();
       },
-      OutputContent::File(_) => {
+      OutputContent::File(_) | OutputContent::Control(_) => {
         error!("Calling readdir on a file: {:?}", entry.path);
         reply.error(EIO as i32);
       }
@@ -295,8 +794,8 @@ impl Filesystem for TransformFs {
   fn read(
     &mut self,
     _req: &Request,
-    ino: u64,
-    _fh: u64,
+    _ino: u64,
+    fh: u64,
     offset: i64,
     size: u32,
     _flags: i32,
@@ -305,26 +804,76 @@ impl Filesystem for TransformFs {
   ) {
     assert!(offset >= 0);
 
+    match self.read_file(fh, offset, size) {
+      Ok(data) => reply.data(&data),
+      Err(errno) => reply.error(errno as i32)
+    };
+  }
+
+  fn write(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    _fh: u64,
+    _offset: i64,
+    data: &[u8],
+    _write_flags: u32,
+    _flags: i32,
+    _lock_owner: Option<u64>,
+    reply: fuser::ReplyWrite,
+  ) {
+    match self.output.inode_map.get(&ino) {
+      Some(OutputEntry { content: OutputContent::Control(ControlFile::Reload), .. }) => {},
+      _ => {
+        reply.error(EROFS as i32);
+        return;
+      }
+    };
+
+    // reload in background so kernel cache can be invalidated
+    let triggered = self.reload_trigger.as_ref().is_some_and(|t| t.send(()).is_ok());
+    if !triggered {
+      let result = self.reload();
+      self.finish_update(result);
+    }
+    reply.written(data.len() as u32);
+  }
+
+  fn setattr(
+    &mut self,
+    _req: &Request<'_>,
+    ino: u64,
+    _mode: Option<u32>,
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+    _size: Option<u64>,
+    _atime: Option<TimeOrNow>,
+    _mtime: Option<TimeOrNow>,
+    _ctime: Option<SystemTime>,
+    _fh: Option<u64>,
+    _crtime: Option<SystemTime>,
+    _chgtime: Option<SystemTime>,
+    _bkuptime: Option<SystemTime>,
+    _flags: Option<u32>,
+    reply: fuser::ReplyAttr,
+  ) {
+    // allow truncating writable control files (e.g. shell redirection)
     let Some(entry) = self.output.inode_map.get(&ino) else {
       reply.error(ENOENT as i32);
       return;
     };
-
-    match &entry.content {
-      OutputContent::File(f) => {
-        match f.read.call::<_, LuaString>((offset, size)) {
-          Ok(data) => {
-            // HACK: as_bytes not available yet
-            reply.data(&data.as_bytes().to_vec());
-          },
-          Err(err) => {
-            error!("Error reading file {:?}: {}", entry.path, err);
-            reply.error(EIO as i32);
-          },
-        };
-      },
-      OutputContent::Dir(_) => {
-        error!("Calling read on a dir {:?}", entry.path);
+    let OutputContent::Control(c) = &entry.content else {
+      reply.error(EROFS as i32);
+      return;
+    };
+    if !c.writable() {
+      reply.error(EACCES as i32);
+      return;
+    }
+    match self.read_metadata(ino, entry) {
+      Ok(attr) => reply.attr(&self.attr_ttl(entry), &attr),
+      Err(err) => {
+        error!("Error reading metadata of file {:?}: {}", entry.path, err);
         reply.error(EIO as i32);
       }
     };
@@ -344,3 +893,369 @@ impl Filesystem for TransformFs {
   }
 }
 
+
+/// Reader of an opened file using its read callback
+///
+/// The file is closed when dropped
+pub struct FileReader<'a> {
+  fs: &'a mut TransformFs,
+  fh: u64,
+  offset: u64,
+  end: u64,
+  /// Size of the file after opening
+  size: u64,
+  /// Max size of each read call
+  chunk_size: u32,
+  closed: bool
+}
+
+impl FileReader<'_> {
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Close the file and return the error of close callback
+  pub fn close(mut self) -> Result<(), Errno> {
+    self.closed = true;
+    self.fs.release_file(self.fh)
+  }
+}
+
+impl Read for FileReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.offset >= self.end {
+      return Ok(0);
+    }
+    let size = (self.end - self.offset)
+      .min(self.chunk_size as u64)
+      .min(buf.len() as u64) as u32;
+    let data = self.fs.read_file(self.fh, self.offset as i64, size)?;
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    self.offset += n as u64;
+    Ok(n)
+  }
+}
+
+impl Drop for FileReader<'_> {
+  fn drop(&mut self) {
+    if !self.closed {
+      let _ = self.fs.release_file(self.fh);
+    }
+  }
+}
+
+/// TransformFs shared between the FUSE session and background threads
+#[derive(Clone)]
+pub struct SharedFs(Arc<Mutex<TransformFs>>);
+
+impl SharedFs {
+  pub fn new(fs: TransformFs) -> Self {
+    Self(Arc::new(Mutex::new(fs)))
+  }
+
+  pub fn lock(&self) -> MutexGuard<'_, TransformFs> {
+    // a panic in a callback shouldn't make the fs unusable
+    self.0.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Update output and invalidate kernel cache of changed entries
+  pub fn refresh(&self, notifier: &Notifier) {
+    let inval = {
+      let mut fs = self.lock();
+      let result = fs.refresh();
+      fs.finish_update(result)
+    };
+    // notify after releasing the lock as the kernel may wait for pending requests
+    inval.notify(notifier);
+  }
+
+  /// Reload script and invalidate kernel cache of the previous output
+  pub fn reload(&self, notifier: &Notifier) {
+    let inval = {
+      let mut fs = self.lock();
+      let result = fs.reload();
+      fs.finish_update(result)
+    };
+    inval.notify(notifier);
+  }
+
+  /// Retry the failed update and invalidate kernel cache of changed entries
+  pub fn retry(&self, notifier: &Notifier) {
+    let inval = {
+      let mut fs = self.lock();
+      let result = fs.retry();
+      fs.finish_update(result)
+    };
+    inval.notify(notifier);
+  }
+}
+
+impl Filesystem for SharedFs {
+  fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+    self.lock().lookup(req, parent, name, reply)
+  }
+
+  fn getattr(&mut self, req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+    self.lock().getattr(req, ino, reply)
+  }
+
+  fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+    self.lock().open(req, ino, flags, reply)
+  }
+
+  fn release(
+    &mut self,
+    req: &Request<'_>,
+    ino: u64,
+    fh: u64,
+    flags: i32,
+    lock_owner: Option<u64>,
+    flush: bool,
+    reply: fuser::ReplyEmpty,
+  ) {
+    self.lock().release(req, ino, fh, flags, lock_owner, flush, reply)
+  }
+
+  fn readdir(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    reply: fuser::ReplyDirectory,
+  ) {
+    self.lock().readdir(req, ino, fh, offset, reply)
+  }
+
+  fn read(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    size: u32,
+    flags: i32,
+    lock_owner: Option<u64>,
+    reply: fuser::ReplyData,
+  ) {
+    self.lock().read(req, ino, fh, offset, size, flags, lock_owner, reply)
+  }
+
+  fn write(
+    &mut self,
+    req: &Request<'_>,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    data: &[u8],
+    write_flags: u32,
+    flags: i32,
+    lock_owner: Option<u64>,
+    reply: fuser::ReplyWrite,
+  ) {
+    self.lock().write(req, ino, fh, offset, data, write_flags, flags, lock_owner, reply)
+  }
+
+  fn setattr(
+    &mut self,
+    req: &Request<'_>,
+    ino: u64,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<TimeOrNow>,
+    mtime: Option<TimeOrNow>,
+    ctime: Option<SystemTime>,
+    fh: Option<u64>,
+    crtime: Option<SystemTime>,
+    chgtime: Option<SystemTime>,
+    bkuptime: Option<SystemTime>,
+    flags: Option<u32>,
+    reply: fuser::ReplyAttr,
+  ) {
+    self.lock().setattr(req, ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime, flags, reply)
+  }
+
+  fn statfs(&mut self, req: &Request<'_>, ino: u64, reply: fuser::ReplyStatfs) {
+    self.lock().statfs(req, ino, reply)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+  /// Transform failing every call
+  struct Failing(Arc<AtomicUsize>);
+
+  impl Transform for Failing {
+    fn transform(&self, _inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+      self.0.fetch_add(1, Ordering::SeqCst);
+      anyhow::bail!("failed")
+    }
+  }
+
+  fn failing_fs(calls: &Arc<AtomicUsize>) -> TransformFs {
+    TransformFsBuilder::new()
+      .transform(Failing(calls.clone()))
+      .config(Config {
+        on_error: OnError::Retry,
+        retry_interval: Duration::from_secs(10),
+        max_retry_interval: Duration::from_secs(25),
+        timeout: Duration::ZERO,
+        ..Config::default()
+      })
+      .build()
+      .unwrap()
+  }
+
+  // delay rounded up to seconds
+  fn delay_secs(fs: &TransformFs) -> Option<u64> {
+    fs.retry_delay().map(|d| d.as_secs() + u64::from(d.subsec_nanos() > 0))
+  }
+
+  #[test]
+  fn retry_backoff() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut fs = failing_fs(&calls);
+    assert_eq!(fs.failures, 1);
+    assert_eq!(delay_secs(&fs), Some(10));
+    fs.failures = 2;
+    assert_eq!(delay_secs(&fs), Some(20));
+    fs.failures = 3;
+    assert_eq!(delay_secs(&fs), Some(25));
+    fs.failures = 100;
+    assert_eq!(delay_secs(&fs), Some(25));
+    fs.last_failure -= Duration::from_secs(30);
+    assert_eq!(fs.retry_delay(), Some(Duration::ZERO));
+
+    fs.config.on_error = OnError::Abort;
+    assert_eq!(fs.retry_delay(), None);
+    fs.failures = 0;
+    fs.config.on_error = OnError::Retry;
+    assert_eq!(fs.retry_delay(), None);
+  }
+
+  /// Transform with no outputs
+  struct Empty(bool);
+
+  impl Transform for Empty {
+    fn transform(&self, _inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+      Ok(Vec::new())
+    }
+
+    fn incremental(&self) -> bool {
+      self.0
+    }
+  }
+
+  #[test]
+  fn snapshot_incremental_only() {
+    for incremental in [false, true] {
+      let mut fs = TransformFsBuilder::new()
+        .transform(Empty(incremental))
+        .inputs(["src"])
+        .build()
+        .unwrap();
+      assert_eq!(fs.input_snapshot.is_empty(), !incremental);
+      fs.refresh().unwrap();
+      assert_eq!(fs.input_snapshot.is_empty(), !incremental);
+    }
+  }
+
+  /// Transform failing until it's fixed
+  struct Flaky(Arc<AtomicBool>);
+
+  impl Transform for Flaky {
+    fn transform(&self, _inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+      if !self.0.load(Ordering::SeqCst) {
+        anyhow::bail!("broken");
+      }
+      Ok(Vec::new())
+    }
+  }
+
+  #[test]
+  fn control_handles_survive_updates() {
+    let fixed = Arc::new(AtomicBool::new(false));
+    let mut fs = TransformFsBuilder::new()
+      .transform(Flaky(fixed.clone()))
+      .config(Config {
+        on_error: OnError::Retry,
+        control_dir: true,
+        error_file: true,
+        ..Config::default()
+      })
+      .build()
+      .unwrap();
+    let open = |fs: &mut TransformFs, path: &str| {
+      let (ino, _) = fs.output().lookup(OsStr::new(path)).unwrap();
+      fs.open_file(ino, O_RDONLY).unwrap().0
+    };
+    let error = open(&mut fs, ERROR_FILE);
+    let status = open(&mut fs, ".transformfs/status");
+    let status_before = fs.read_file(status, 0, 4096).unwrap();
+
+    fixed.store(true, Ordering::SeqCst);
+    let result = fs.retry();
+    fs.finish_update(result);
+    assert!(fs.output().lookup(OsStr::new(ERROR_FILE)).is_none());
+
+    let error_data = String::from_utf8(fs.read_file(error, 0, 4096).unwrap()).unwrap();
+    assert!(error_data.contains("broken"));
+    assert_eq!(fs.read_file(status, 0, 4096).unwrap(), status_before);
+    fs.release_file(error).unwrap();
+    fs.release_file(status).unwrap();
+    assert_eq!(fs.read_file(status, 0, 4096), Err(EBADF));
+  }
+
+  /// Transform with a static and a dynamic size file
+  struct Sized;
+
+  impl Transform for Sized {
+    fn transform(&self, _inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+      let file = || OutputFile::new(OutputFileMetadata::new(0), |_, _| Ok(Vec::new()));
+      Ok(vec![
+        OutputEntry::file("static", file()),
+        OutputEntry::file("dynamic", file().with_dynamic_size(|| Some(1)))
+      ])
+    }
+  }
+
+  #[test]
+  fn dynamic_size_not_cached() {
+    let mut fs = TransformFsBuilder::new()
+      .transform(Sized)
+      .config(Config { control_dir: true, ..Config::default() })
+      .build()
+      .unwrap();
+    let ttl = |fs: &TransformFs, path: &str| fs.attr_ttl(fs.output().lookup(OsStr::new(path)).unwrap().1);
+    assert_eq!(ttl(&fs, "static"), fs.config.timeout);
+    assert_eq!(ttl(&fs, ""), fs.config.timeout);
+    assert_eq!(ttl(&fs, "dynamic"), Duration::ZERO);
+    assert_eq!(ttl(&fs, ".transformfs/status"), Duration::ZERO);
+
+    // replaced while open
+    let (ino, _) = fs.output().lookup(OsStr::new("dynamic")).unwrap();
+    fs.open_file(ino, O_RDONLY).unwrap();
+    fs.refresh().unwrap();
+    assert_eq!(fs.open_file_attr(ino).map(|(attr, ttl)| (attr.size, ttl)), Some((1, Duration::ZERO)));
+  }
+
+  #[test]
+  fn update_waits_for_backoff() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut fs = failing_fs(&calls);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    // the timeout has passed but not the backoff
+    fs.update();
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    fs.last_failure -= Duration::from_secs(10);
+    fs.update();
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(fs.failures, 2);
+  }
+}
@@ -13,9 +13,56 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::{ffi::OsString, path::Path};
+use std::{collections::{HashMap, HashSet}, ffi::OsString, fs::{self, FileType}, os::unix::fs::FileTypeExt, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
 use log::warn;
+use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
+use crate::output::OutputFile;
+
+/// Type of an input entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputKind {
+  File,
+  Dir,
+  Symlink,
+  Fifo,
+  Socket,
+  BlockDevice,
+  CharDevice
+}
+
+impl InputKind {
+  pub fn from_file_type(t: FileType) -> Self {
+    if t.is_dir() {
+      InputKind::Dir
+    } else if t.is_symlink() {
+      InputKind::Symlink
+    } else if t.is_fifo() {
+      InputKind::Fifo
+    } else if t.is_socket() {
+      InputKind::Socket
+    } else if t.is_block_device() {
+      InputKind::BlockDevice
+    } else if t.is_char_device() {
+      InputKind::CharDevice
+    } else {
+      InputKind::File
+    }
+  }
+
+  /// Name of the type passed to Lua
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      InputKind::File => "file",
+      InputKind::Dir => "dir",
+      InputKind::Symlink => "symlink",
+      InputKind::Fifo => "fifo",
+      InputKind::Socket => "socket",
+      InputKind::BlockDevice => "block_device",
+      InputKind::CharDevice => "char_device"
+    }
+  }
+}
 
 // read all files under a path
 pub fn read_files(root: impl AsRef<Path>) -> impl Iterator<Item = OsString> {
@@ -37,3 +84,156 @@ pub fn read_files(root: impl AsRef<Path>) -> impl Iterator<Item = OsString> {
       }
     })
 }
+
+// read all entries (including dirs and special files) under a path
+// symlinks are not followed
+pub fn read_entries(root: impl AsRef<Path>) -> impl Iterator<Item = (OsString, InputKind)> {
+  WalkDir::new(root)
+    .into_iter()
+    .filter_map(|r| {
+      match r {
+        Ok(e) => Some((
+          e.path().as_os_str().to_os_string(),
+          InputKind::from_file_type(e.file_type())
+        )),
+        Err(err) => {
+          warn!("error reading entry: {}", err);
+          None
+        }
+      }
+    })
+}
+
+/// Input entry passed to transform
+#[derive(Clone, Debug)]
+pub struct Input {
+  pub path: OsString,
+  pub kind: InputKind,
+  /// Input root (from the command line) containing the path
+  /// (None for outputs of the previous stage and removed inputs)
+  pub root: Option<PathBuf>,
+  /// Output of the previous stage in a pipeline (read in-process instead of from path)
+  pub file: Option<Arc<OutputFile>>
+}
+
+// read inputs under all roots
+pub fn read_inputs(roots: &[PathBuf], all_entries: bool) -> Vec<Input> {
+  if all_entries {
+    roots.iter()
+      .flat_map(|root| {
+        read_entries(root).map(|(path, kind)| Input { path, kind, root: Some(root.clone()), file: None })
+      })
+      .collect()
+  } else {
+    roots.iter()
+      .flat_map(|root| {
+        read_files(root).map(|path| Input { path, kind: InputKind::File, root: Some(root.clone()), file: None })
+      })
+      .collect()
+  }
+}
+
+/// State of an input used to detect changes
+#[derive(Clone, PartialEq, Eq)]
+pub struct InputState {
+  pub kind: InputKind,
+  pub size: u64,
+  pub mtime: Option<SystemTime>
+}
+
+/// States of all inputs
+pub type InputSnapshot = HashMap<OsString, InputState>;
+
+pub fn snapshot(inputs: &[Input]) -> InputSnapshot {
+  inputs.iter()
+    .map(|i| {
+      // fall back to the link itself for dangling symlinks
+      let metadata = fs::metadata(&i.path).or_else(|_| fs::symlink_metadata(&i.path));
+      let (size, mtime) = match metadata {
+        Ok(m) => (m.len(), m.modified().ok()),
+        Err(err) => {
+          warn!("error reading metadata of {:?}: {}", i.path, err);
+          (0, None)
+        }
+      };
+      (i.path.clone(), InputState { kind: i.kind, size, mtime })
+    })
+    .collect()
+}
+
+/// Inputs changed between two snapshots
+#[derive(Default)]
+pub struct InputChanges {
+  pub added: Vec<Input>,
+  pub removed: Vec<Input>,
+  pub modified: Vec<Input>
+}
+
+impl InputChanges {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+  }
+}
+
+// compare new inputs and their snapshot with the old snapshot
+pub fn diff_inputs(old: &InputSnapshot, inputs: &[Input], new: &InputSnapshot) -> InputChanges {
+  let mut changes = InputChanges::default();
+  let mut seen = HashSet::new();
+  for i in inputs {
+    seen.insert(&i.path);
+    match old.get(&i.path) {
+      None => changes.added.push(i.clone()),
+      Some(s) => {
+        if Some(s) != new.get(&i.path) {
+          changes.modified.push(i.clone());
+        }
+      }
+    };
+  }
+  changes.removed = old.iter()
+    .filter(|(path, _)| !seen.contains(path))
+    .map(|(path, s)| Input { path: path.clone(), kind: s.kind, root: None, file: None })
+    .collect();
+  changes.removed.sort_by(|a, b| a.path.cmp(&b.path));
+  changes
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn input(path: &str) -> Input {
+    Input { path: path.into(), kind: InputKind::File, root: None, file: None }
+  }
+
+  fn state(size: u64) -> InputState {
+    InputState { kind: InputKind::File, size, mtime: None }
+  }
+
+  fn paths(inputs: &[Input]) -> Vec<&OsString> {
+    inputs.iter().map(|i| &i.path).collect()
+  }
+
+  #[test]
+  fn diff_snapshots() {
+    let old = InputSnapshot::from([
+      ("a".into(), state(1)),
+      ("b".into(), state(2)),
+      ("d".into(), state(4)),
+      ("c".into(), state(3))
+    ]);
+    let new = InputSnapshot::from([
+      ("a".into(), state(1)),
+      ("b".into(), state(5)),
+      ("e".into(), state(6))
+    ]);
+    let changes = diff_inputs(&old, &[input("a"), input("b"), input("e")], &new);
+    assert_eq!(paths(&changes.added), ["e"]);
+    assert_eq!(paths(&changes.modified), ["b"]);
+    // sorted by path
+    assert_eq!(paths(&changes.removed), ["c", "d"]);
+    assert!(!changes.is_empty());
+
+    assert!(diff_inputs(&new, &[input("a"), input("b"), input("e")], &new).is_empty());
+  }
+}
@@ -0,0 +1,321 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{collections::{HashMap, HashSet}, ffi::OsString, io::{self, ErrorKind}, path::{Path, PathBuf}, sync::{mpsc, Arc, Condvar, Mutex, PoisonError}, thread::{self, JoinHandle}, time::Duration};
+use fuser::Notifier;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{error, info, warn};
+use signal_hook::{consts::SIGHUP, iterator::{Handle, Signals}};
+use walkdir::WalkDir;
+use crate::transformfs::SharedFs;
+
+pub struct WatchConfig {
+  /// Input dirs/files to watch (dirs are watched recursively)
+  pub inputs: Vec<PathBuf>,
+  /// User scripts of all stages
+  pub scripts: Vec<PathBuf>,
+  /// Time to wait for events to settle before rerunning transform
+  pub debounce: Duration
+}
+
+/// Interval to check whether a failed update needs to be retried
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval to check for inotify events (and whether to stop)
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Flag to stop background threads
+#[derive(Clone, Default)]
+struct Shutdown(Arc<(Mutex<bool>, Condvar)>);
+
+impl Shutdown {
+  fn stop(&self) {
+    let (stopped, cvar) = &*self.0;
+    *stopped.lock().unwrap_or_else(PoisonError::into_inner) = true;
+    cvar.notify_all();
+  }
+
+  /// Sleep until timeout or stopped and return whether it's stopped
+  fn wait(&self, timeout: Duration) -> bool {
+    let (stopped, cvar) = &*self.0;
+    let guard = stopped.lock().unwrap_or_else(PoisonError::into_inner);
+    let (guard, _) = cvar.wait_timeout_while(guard, timeout, |s| !*s)
+      .unwrap_or_else(PoisonError::into_inner);
+    *guard
+  }
+}
+
+/// Background threads of a mount (stopped and joined when dropped)
+#[derive(Default)]
+pub struct Background {
+  shutdown: Shutdown,
+  threads: Vec<JoinHandle<()>>,
+  /// Handle to stop waiting for SIGHUP
+  signals: Option<Handle>,
+  /// Fs holding the reload trigger (dropped to end the reloader)
+  reload_fs: Option<SharedFs>
+}
+
+impl Background {
+  /// Stop all threads and wait for them to finish
+  pub fn stop(&mut self) {
+    self.shutdown.stop();
+    if let Some(signals) = self.signals.take() {
+      signals.close();
+    }
+    if let Some(fs) = self.reload_fs.take() {
+      fs.lock().remove_reload_trigger();
+    }
+    for thread in self.threads.drain(..) {
+      if thread.join().is_err() {
+        error!("Background thread panicked");
+      }
+    }
+  }
+}
+
+impl Drop for Background {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+fn watch_mask() -> WatchMask {
+  WatchMask::CREATE
+    | WatchMask::DELETE
+    | WatchMask::MODIFY
+    | WatchMask::CLOSE_WRITE
+    | WatchMask::ATTRIB
+    | WatchMask::MOVED_FROM
+    | WatchMask::MOVED_TO
+}
+
+/// Kinds of changes seen in events
+#[derive(Default)]
+struct Changes {
+  inputs: bool,
+  script: bool
+}
+
+struct Watcher {
+  inotify: Inotify,
+  /// Names of interest in each watched dir (None to accept all entries)
+  watches: HashMap<WatchDescriptor, Option<HashSet<OsString>>>,
+  /// Watched dir and name of each script
+  scripts: Vec<(WatchDescriptor, OsString)>
+}
+
+impl Watcher {
+  fn add(&mut self, dir: &Path, name: Option<OsString>) -> Option<WatchDescriptor> {
+    match self.inotify.watches().add(dir, watch_mask()) {
+      Ok(wd) => {
+        let names = self.watches.entry(wd.clone()).or_insert_with(|| Some(HashSet::new()));
+        match name {
+          Some(n) => {
+            if let Some(names) = names {
+              names.insert(n);
+            }
+          },
+          None => *names = None
+        };
+        Some(wd)
+      },
+      Err(err) => {
+        warn!("Failed to watch {:?}: {}", dir, err);
+        None
+      }
+    }
+  }
+
+  // watch the parent dir instead of the file
+  // as editors usually replace files by renaming
+  fn add_file(&mut self, path: &Path) -> Option<(WatchDescriptor, OsString)> {
+    let Some(name) = path.file_name() else {
+      warn!("Failed to watch {:?}: invalid file name", path);
+      return None;
+    };
+    let parent = match path.parent() {
+      Some(p) if !p.as_os_str().is_empty() => p,
+      _ => Path::new(".")
+    };
+    let wd = self.add(parent, Some(name.to_os_string()))?;
+    Some((wd, name.to_os_string()))
+  }
+
+  // inotify is not recursive so every dir needs a watch
+  // (adding an existing watch is a no-op)
+  fn add_watches(&mut self, config: &WatchConfig) {
+    for root in &config.inputs {
+      if root.is_dir() {
+        let dirs = WalkDir::new(root)
+          .into_iter()
+          .filter_map(Result::ok)
+          .filter(|e| e.file_type().is_dir());
+        for e in dirs {
+          self.add(e.path(), None);
+        }
+      } else {
+        self.add_file(root);
+      }
+    }
+    self.scripts = config.scripts.iter()
+      .filter_map(|s| self.add_file(s))
+      .collect();
+  }
+
+  /// Read pending events and record relevant changes
+  ///
+  /// Return false if no relevant event is read
+  fn read_events(&mut self, buffer: &mut [u8], changes: &mut Changes) -> io::Result<bool> {
+    let events = match self.inotify.read_events(buffer) {
+      Ok(events) => events,
+      Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+      Err(err) => return Err(err)
+    };
+
+    let mut relevant = false;
+    for event in events {
+      if event.mask.contains(EventMask::Q_OVERFLOW) {
+        // events lost so reload everything
+        changes.script = true;
+        relevant = true;
+        continue;
+      }
+      if event.mask.contains(EventMask::IGNORED) {
+        // watched dir removed
+        self.watches.remove(&event.wd);
+        continue;
+      }
+      let is_script = event.name.is_some_and(|n| {
+        self.scripts.iter().any(|(wd, name)| *wd == event.wd && name == n)
+      });
+      if is_script {
+        changes.script = true;
+        relevant = true;
+        continue;
+      }
+      let is_input = match self.watches.get(&event.wd) {
+        Some(None) => true,
+        Some(Some(names)) => event.name.is_some_and(|n| names.contains(n)),
+        None => false
+      };
+      changes.inputs |= is_input;
+      relevant |= is_input;
+    }
+    Ok(relevant)
+  }
+
+  fn run(mut self, fs: SharedFs, notifier: Notifier, config: WatchConfig, shutdown: Shutdown) {
+    let mut buffer = [0; 4096];
+    loop {
+      let mut changes = Changes::default();
+      match self.read_events(&mut buffer, &mut changes) {
+        Ok(true) => {},
+        Ok(false) => {
+          if shutdown.wait(WATCH_POLL_INTERVAL) {
+            return;
+          }
+          continue;
+        },
+        Err(err) => {
+          error!("Failed to read inotify events: {}", err);
+          return;
+        }
+      };
+      // wait until events settle
+      loop {
+        if shutdown.wait(config.debounce) {
+          return;
+        }
+        match self.read_events(&mut buffer, &mut changes) {
+          Ok(true) => continue,
+          Ok(false) => break,
+          Err(err) => {
+            error!("Failed to read inotify events: {}", err);
+            return;
+          }
+        };
+      }
+
+      if changes.script {
+        fs.reload(&notifier);
+      } else if changes.inputs {
+        info!("Update output on change");
+        fs.refresh(&notifier);
+      }
+      // watch newly created dirs
+      self.add_watches(&config);
+    }
+  }
+}
+
+/// Watch inputs and script in background and rerun transform on changes
+pub fn spawn(fs: SharedFs, notifier: Notifier, config: WatchConfig, background: &mut Background) -> anyhow::Result<()> {
+  let mut watcher = Watcher {
+    inotify: Inotify::init()?,
+    watches: HashMap::new(),
+    scripts: Vec::new()
+  };
+  watcher.add_watches(&config);
+  let shutdown = background.shutdown.clone();
+  background.threads.push(thread::spawn(move || watcher.run(fs, notifier, config, shutdown)));
+  Ok(())
+}
+
+/// Reload script in background on writes to the control file (and SIGHUP if enabled)
+///
+/// The reloader ends once the trigger in fs and the SIGHUP thread are dropped
+pub fn spawn_reloader(fs: SharedFs, notifier: Notifier, sighup: bool, background: &mut Background) -> anyhow::Result<()> {
+  let (tx, rx) = mpsc::channel();
+  fs.lock().set_reload_trigger(tx.clone());
+  background.reload_fs = Some(fs.clone());
+
+  if sighup {
+    let mut signals = Signals::new([SIGHUP])?;
+    background.signals = Some(signals.handle());
+    background.threads.push(thread::spawn(move || {
+      for _ in signals.forever() {
+        if tx.send(()).is_err() {
+          return;
+        }
+      }
+    }));
+  }
+  background.threads.push(thread::spawn(move || {
+    for _ in rx {
+      fs.reload(&notifier);
+    }
+  }));
+  Ok(())
+}
+
+/// Retry failed updates in background with backoff
+pub fn spawn_retrier(fs: SharedFs, notifier: Notifier, background: &mut Background) {
+  let shutdown = background.shutdown.clone();
+  background.threads.push(thread::spawn(move || loop {
+    let delay = fs.lock().retry_delay();
+    let wait = match delay {
+      Some(d) if d.is_zero() => {
+        fs.retry(&notifier);
+        continue;
+      },
+      // updates in other threads may change the delay
+      Some(d) => d.min(RETRY_POLL_INTERVAL),
+      None => RETRY_POLL_INTERVAL
+    };
+    if shutdown.wait(wait) {
+      return;
+    }
+  }));
+}
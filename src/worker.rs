@@ -0,0 +1,734 @@
+// Copyright (C) 2024  DCsunset
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{collections::HashMap, error::Error, ffi::OsString, fmt, fs::File, io::{self, BufReader, BufWriter, Read, Write}, mem, os::fd::{AsFd, AsRawFd}, path::PathBuf, process::{Child, ChildStdin, ChildStdout, Command, Stdio}, sync::{Arc, Mutex}, time::Duration};
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::{lua::{LuaTransform, ScriptOptions}, output::{OutputContent, OutputEntry, OutputFile, OutputFileMetadata, OutputPatch}, transform::{LimitExceeded, Transform, TransformLoader}, utils::{Input, InputKind}};
+
+/// Argument to run the program as a worker
+pub const WORKER_ARG: &str = "worker";
+
+/// Error of a call into a worker that crashed
+/// (outputs of the crashed worker are stale until reloaded)
+#[derive(Debug)]
+pub struct WorkerCrashed;
+
+impl fmt::Display for WorkerCrashed {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "worker process crashed")
+  }
+}
+
+impl Error for WorkerCrashed {}
+
+/// Output file whose callbacks run on the other side of the pipe
+#[derive(Serialize, Deserialize)]
+struct WireFile {
+  id: u64,
+  metadata: OutputFileMetadata,
+  open: bool,
+  close: bool,
+  /// Size only known after opening (sent in the response of open)
+  dynamic_size: bool
+}
+
+impl WireFile {
+  fn new(id: u64, file: &OutputFile) -> Self {
+    Self {
+      id,
+      metadata: file.metadata.clone(),
+      open: file.open.is_some(),
+      close: file.close.is_some(),
+      dynamic_size: file.dynamic_size.is_some()
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireInput {
+  path: OsString,
+  kind: InputKind,
+  /// Output file of the previous stage
+  file: Option<WireFile>
+}
+
+#[derive(Serialize, Deserialize)]
+struct WireEntry {
+  path: OsString,
+  file: WireFile
+}
+
+/// Call into an output file
+#[derive(Serialize, Deserialize)]
+enum FileCall {
+  Read { id: u64, offset: u64, size: u32 },
+  Open { id: u64 },
+  Close { id: u64 }
+}
+
+impl FileCall {
+  fn id(&self) -> u64 {
+    match *self {
+      FileCall::Read { id, .. } | FileCall::Open { id } | FileCall::Close { id } => id
+    }
+  }
+
+  // return data of reads and the size (little-endian) after opening
+  fn run(&self, file: &OutputFile) -> anyhow::Result<Vec<u8>> {
+    match *self {
+      FileCall::Read { offset, size, .. } => (file.read)(offset, size),
+      FileCall::Open { .. } => {
+        file.open.as_ref().map_or(Ok(()), |f| f())?;
+        Ok(file.size().to_le_bytes().to_vec())
+      },
+      FileCall::Close { .. } => {
+        file.close.as_ref().map_or(Ok(()), |f| f())?;
+        Ok(Vec::new())
+      }
+    }
+  }
+}
+
+/// Message from the parent to the worker
+#[derive(Serialize, Deserialize)]
+enum Request {
+  Load { script: PathBuf, options: ScriptOptions },
+  Transform { inputs: Vec<WireInput> },
+  OnChange { added: Vec<WireInput>, removed: Vec<WireInput>, modified: Vec<WireInput> },
+  File(FileCall),
+  /// Drop output files no longer used
+  Release { ids: Vec<u64> },
+  /// Result of a callback (data of reads in the payload)
+  Reply { error: Option<String> }
+}
+
+/// Message from the worker to the parent
+#[derive(Serialize, Deserialize)]
+enum Response {
+  Loaded { incremental: bool },
+  Outputs(Vec<WireEntry>),
+  Patch(Option<(Vec<WireEntry>, Vec<OsString>)>),
+  /// Success (data of reads in the payload)
+  Done,
+  /// Size of an output file after opening
+  Opened { size: u64 },
+  /// Time limit exceeded by the call if it timed out
+  Error { message: String, timed_out: Option<Duration> },
+  /// Call into an input file from the previous stage
+  Callback(FileCall),
+  /// Input files dropped by the worker (sent before the response)
+  ReleasedInputs(Vec<u64>)
+}
+
+fn unexpected() -> anyhow::Error {
+  anyhow::anyhow!("Unexpected message from worker")
+}
+
+/// Framed messages over a pipe
+///
+/// Each frame has the lengths of the JSON header and the raw payload followed by both
+struct Channel<R: Read, W: Write> {
+  reader: BufReader<R>,
+  writer: BufWriter<W>
+}
+
+impl<R: Read, W: Write> Channel<R, W> {
+  fn new(reader: R, writer: W) -> Self {
+    Self {
+      reader: BufReader::new(reader),
+      writer: BufWriter::new(writer)
+    }
+  }
+
+  fn send<T: Serialize>(&mut self, msg: &T, data: &[u8]) -> io::Result<()> {
+    let header = serde_json::to_vec(msg)?;
+    self.writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    self.writer.write_all(&header)?;
+    self.writer.write_all(data)?;
+    self.writer.flush()
+  }
+
+  fn recv<T: DeserializeOwned>(&mut self) -> io::Result<(T, Vec<u8>)> {
+    let mut lens = [0u8; 8];
+    self.reader.read_exact(&mut lens)?;
+    let header_len = u32::from_le_bytes([lens[0], lens[1], lens[2], lens[3]]) as usize;
+    let data_len = u32::from_le_bytes([lens[4], lens[5], lens[6], lens[7]]) as usize;
+    let mut header = vec![0; header_len];
+    self.reader.read_exact(&mut header)?;
+    let mut data = vec![0; data_len];
+    self.reader.read_exact(&mut data)?;
+    Ok((serde_json::from_slice(&header)?, data))
+  }
+}
+
+type ServerChannel = Arc<Mutex<Channel<File, File>>>;
+
+// call into an input file of the parent while handling a request
+fn callback(channel: &ServerChannel, call: FileCall) -> anyhow::Result<Vec<u8>> {
+  let mut channel = channel.lock().unwrap();
+  channel.send(&Response::Callback(call), &[])?;
+  match channel.recv()? {
+    (Request::Reply { error: None }, data) => Ok(data),
+    (Request::Reply { error: Some(err) }, _) => Err(anyhow::anyhow!(err)),
+    _ => anyhow::bail!("Unexpected request during callback")
+  }
+}
+
+/// Reference to an input file of the parent (released when dropped)
+struct InputRef {
+  id: u64,
+  released: Arc<Mutex<Vec<u64>>>
+}
+
+impl InputRef {
+  fn read(&self, channel: &ServerChannel, offset: u64, size: u32) -> anyhow::Result<Vec<u8>> {
+    callback(channel, FileCall::Read { id: self.id, offset, size })
+  }
+}
+
+impl Drop for InputRef {
+  fn drop(&mut self) {
+    self.released.lock().unwrap().push(self.id);
+  }
+}
+
+/// State of the worker process
+struct Server {
+  channel: ServerChannel,
+  transform: Option<LuaTransform>,
+  /// Output files by id until released
+  files: HashMap<u64, Arc<OutputFile>>,
+  next_id: u64,
+  /// Input files dropped since the last response
+  released_inputs: Arc<Mutex<Vec<u64>>>
+}
+
+impl Server {
+  fn transform(&self) -> anyhow::Result<&LuaTransform> {
+    self.transform.as_ref().ok_or_else(|| anyhow::anyhow!("Script not loaded"))
+  }
+
+  fn inputs(&self, inputs: Vec<WireInput>) -> Vec<Input> {
+    inputs.into_iter()
+      .map(|i| Input {
+        path: i.path,
+        kind: i.kind,
+        root: None,
+        file: i.file.map(|f| {
+          let id = f.id;
+          let channel = self.channel.clone();
+          let input = InputRef { id, released: self.released_inputs.clone() };
+          // the reference lives as long as the read function
+          let mut file = OutputFile::new(f.metadata, move |offset, size| input.read(&channel, offset, size));
+          let size = Arc::new(Mutex::new(None));
+          // open returns the size of files with dynamic size
+          if f.open || f.dynamic_size {
+            let channel = self.channel.clone();
+            let size = size.clone();
+            file = file.on_open(move || {
+              let data = callback(&channel, FileCall::Open { id })?;
+              if let Ok(bytes) = data.try_into() {
+                *size.lock().unwrap() = Some(u64::from_le_bytes(bytes));
+              }
+              Ok(())
+            });
+          }
+          if f.dynamic_size {
+            file = file.with_dynamic_size(move || *size.lock().unwrap());
+          }
+          if f.close {
+            let channel = self.channel.clone();
+            file = file.on_close(move || callback(&channel, FileCall::Close { id }).map(|_| ()));
+          }
+          Arc::new(file)
+        })
+      })
+      .collect()
+  }
+
+  // keep output files to serve calls from the parent
+  fn register(&mut self, outputs: Vec<OutputEntry>) -> Vec<WireEntry> {
+    outputs.into_iter()
+      .filter_map(|e| match e.content {
+        OutputContent::File(f) => {
+          let id = self.next_id;
+          self.next_id += 1;
+          let file = WireFile::new(id, &f);
+          self.files.insert(id, f);
+          Some(WireEntry { path: e.path, file })
+        },
+        // scripts only output files
+        OutputContent::Dir(_) | OutputContent::Control(_) => None
+      })
+      .collect()
+  }
+
+  fn handle(&mut self, request: Request) -> anyhow::Result<(Response, Vec<u8>)> {
+    let response = match request {
+      Request::Load { script, options } => {
+        let transform = LuaTransform::load(&script, &options)?;
+        let incremental = transform.incremental();
+        self.transform = Some(transform);
+        self.files.clear();
+        Response::Loaded { incremental }
+      },
+      Request::Transform { inputs } => {
+        let inputs = self.inputs(inputs);
+        let outputs = self.transform()?.transform(&inputs)?;
+        Response::Outputs(self.register(outputs))
+      },
+      Request::OnChange { added, removed, modified } => {
+        let (added, removed, modified) = (self.inputs(added), self.inputs(removed), self.inputs(modified));
+        let patch = self.transform()?.on_change(&added, &removed, &modified)?;
+        Response::Patch(patch.map(|p| (self.register(p.add), p.remove)))
+      },
+      Request::File(call) => {
+        let file = self.files.get(&call.id())
+          .ok_or_else(|| anyhow::anyhow!("Unknown output file {}", call.id()))?;
+        let data = call.run(file)?;
+        if let FileCall::Open { .. } = call {
+          Response::Opened { size: file.size() }
+        } else {
+          return Ok((Response::Done, data));
+        }
+      },
+      Request::Release { ids } => {
+        for id in ids {
+          self.files.remove(&id);
+        }
+        Response::Done
+      },
+      Request::Reply { .. } => anyhow::bail!("Unexpected reply without callback")
+    };
+    Ok((response, Vec::new()))
+  }
+}
+
+/// Serve requests of the parent over stdin/stdout until stdin is closed
+///
+/// Programs mounting with `Config::isolate` must call this when run with [`WORKER_ARG`]
+pub fn serve() -> anyhow::Result<()> {
+  // keep the pipes for messages so that scripts can't corrupt them:
+  // output of scripts (e.g. print) goes to stderr and reads of stdin (e.g. io.read) get EOF
+  let input = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+  let out = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+  let null = File::open("/dev/null")?;
+  if unsafe { nix::libc::dup2(null.as_raw_fd(), nix::libc::STDIN_FILENO) } < 0
+    || unsafe { nix::libc::dup2(nix::libc::STDERR_FILENO, nix::libc::STDOUT_FILENO) } < 0 {
+    return Err(io::Error::last_os_error().into());
+  }
+  let channel = Arc::new(Mutex::new(Channel::new(input, out)));
+  let mut server = Server {
+    channel: channel.clone(),
+    transform: None,
+    files: HashMap::new(),
+    next_id: 0,
+    released_inputs: Arc::default()
+  };
+  loop {
+    let received = channel.lock().unwrap().recv::<Request>();
+    let request = match received {
+      Ok((request, _)) => request,
+      // parent exited or dropped the worker
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+      Err(err) => return Err(err.into())
+    };
+    let (response, data) = server.handle(request).unwrap_or_else(|err| {
+      let response = match err.downcast_ref::<LimitExceeded>() {
+        // the parent adds the limit back as the root cause
+        Some(LimitExceeded::Time(limit)) => Response::Error {
+          timed_out: Some(*limit),
+          message: err.chain()
+            .filter(|e| !e.is::<LimitExceeded>())
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(": ")
+        },
+        _ => Response::Error {
+          timed_out: None,
+          message: format!("{:#}", err)
+        }
+      };
+      (response, Vec::new())
+    });
+    let mut channel = channel.lock().unwrap();
+    let released = mem::take(&mut *server.released_inputs.lock().unwrap());
+    if !released.is_empty() {
+      channel.send(&Response::ReleasedInputs(released), &[])?;
+    }
+    channel.send(&response, &data)?;
+  }
+}
+
+type Inputs = HashMap<u64, Arc<OutputFile>>;
+
+// send a request and serve callbacks of the worker until its response
+fn exchange(channel: &mut Channel<ChildStdout, ChildStdin>, inputs: &mut Inputs, request: &Request) -> io::Result<(Response, Vec<u8>)> {
+  channel.send(request, &[])?;
+  loop {
+    let call = match channel.recv()? {
+      (Response::Callback(call), _) => call,
+      (Response::ReleasedInputs(ids), _) => {
+        for id in ids {
+          inputs.remove(&id);
+        }
+        continue;
+      },
+      response => return Ok(response)
+    };
+    let result = match inputs.get(&call.id()) {
+      Some(file) => call.run(file),
+      None => Err(anyhow::anyhow!("Unknown input file {}", call.id()))
+    };
+    match result {
+      Ok(data) => channel.send(&Request::Reply { error: None }, &data)?,
+      Err(err) => channel.send(&Request::Reply { error: Some(format!("{:#}", err)) }, &[])?
+    };
+  }
+}
+
+struct Process {
+  child: Child,
+  channel: Channel<ChildStdout, ChildStdin>
+}
+
+/// Supervisor of the worker process of a script
+struct Worker {
+  program: PathBuf,
+  script: PathBuf,
+  options: ScriptOptions,
+  /// None before start and after a crash (restarted on the next call)
+  process: Option<Process>,
+  /// Incremented on each start so that outputs of a crashed worker become stale
+  generation: u64,
+  incremental: bool,
+  /// Input files of the previous stage readable by the worker until it releases them
+  inputs: Inputs,
+  next_input: u64
+}
+
+impl Worker {
+  fn start(&mut self) -> anyhow::Result<()> {
+    let mut child = Command::new(&self.program)
+      .arg(WORKER_ARG)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .spawn()
+      .map_err(|e| anyhow::anyhow!("Failed to start worker {:?}: {}", self.program, e))?;
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+      unreachable!("stdio of worker is piped");
+    };
+    info!("Started worker {} for {:?}", child.id(), self.script);
+    self.process = Some(Process {
+      child,
+      channel: Channel::new(stdout, stdin)
+    });
+    self.generation += 1;
+
+    let load = Request::Load {
+      script: self.script.clone(),
+      options: self.options.clone()
+    };
+    match self.exchange(&load) {
+      Ok((Response::Loaded { incremental }, _)) => {
+        self.incremental = incremental;
+        Ok(())
+      },
+      result => {
+        self.stop();
+        Err(result.err().unwrap_or_else(unexpected))
+      }
+    }
+  }
+
+  fn stop(&mut self) {
+    let Some(mut process) = self.process.take() else {
+      return;
+    };
+    // the worker may have exited already
+    let _ = process.child.kill();
+    self.inputs.clear();
+    match process.child.wait() {
+      Ok(status) => info!("Worker for {:?} exited: {}", self.script, status),
+      Err(err) => warn!("Failed to wait for worker of {:?}: {}", self.script, err)
+    }
+  }
+
+  fn exchange(&mut self, request: &Request) -> anyhow::Result<(Response, Vec<u8>)> {
+    let Some(process) = &mut self.process else {
+      return Err(WorkerCrashed.into());
+    };
+    match exchange(&mut process.channel, &mut self.inputs, request) {
+      Ok((Response::Error { message, timed_out }, _)) => Err(match timed_out {
+        Some(limit) if message.is_empty() => LimitExceeded::Time(limit).into(),
+        Some(limit) => anyhow::Error::new(LimitExceeded::Time(limit)).context(message),
+        None => anyhow::anyhow!(message)
+      }),
+      Ok(response) => Ok(response),
+      Err(err) => {
+        error!("Worker for {:?} crashed: {}", self.script, err);
+        self.stop();
+        Err(WorkerCrashed.into())
+      }
+    }
+  }
+
+  // start the worker if needed and send released files before the request
+  fn call(&mut self, request: &Request, released: &Mutex<Vec<(u64, u64)>>) -> anyhow::Result<(Response, Vec<u8>)> {
+    if self.process.is_none() {
+      self.start()?;
+    }
+    let ids: Vec<u64> = mem::take(&mut *released.lock().unwrap())
+      .into_iter()
+      .filter(|(generation, _)| *generation == self.generation)
+      .map(|(_, id)| id)
+      .collect();
+    if !ids.is_empty() {
+      self.exchange(&Request::Release { ids })?;
+    }
+    self.exchange(request)
+  }
+
+  fn register_inputs(&mut self, inputs: &[Input]) -> Vec<WireInput> {
+    inputs.iter()
+      .map(|i| WireInput {
+        path: i.path.clone(),
+        kind: i.kind,
+        file: i.file.as_ref().map(|f| {
+          let id = self.next_input;
+          self.next_input += 1;
+          self.inputs.insert(id, f.clone());
+          WireFile::new(id, f)
+        })
+      })
+      .collect()
+  }
+}
+
+impl Drop for Worker {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+/// Worker shared by its transform and output files
+struct Shared {
+  worker: Mutex<Worker>,
+  /// Output files dropped as (generation, id)
+  released: Mutex<Vec<(u64, u64)>>
+}
+
+/// Output file in a worker
+struct FileHandle {
+  shared: Arc<Shared>,
+  generation: u64,
+  id: u64,
+  /// Size returned by the last open
+  size: Mutex<Option<u64>>
+}
+
+impl FileHandle {
+  fn call(&self, call: FileCall) -> anyhow::Result<Vec<u8>> {
+    let mut worker = self.shared.worker.lock().unwrap();
+    if worker.generation != self.generation || worker.process.is_none() {
+      return Err(WorkerCrashed.into());
+    }
+    match worker.call(&Request::File(call), &self.shared.released)? {
+      (Response::Done, data) => Ok(data),
+      (Response::Opened { size }, _) => {
+        *self.size.lock().unwrap() = Some(size);
+        Ok(Vec::new())
+      },
+      _ => Err(unexpected())
+    }
+  }
+}
+
+impl Drop for FileHandle {
+  fn drop(&mut self) {
+    // sent with the next call as the worker may be busy
+    self.shared.released.lock().unwrap().push((self.generation, self.id));
+  }
+}
+
+/// Transform of a script running in a worker process
+pub struct WorkerTransform(Arc<Shared>);
+
+impl WorkerTransform {
+  fn outputs(&self, generation: u64, entries: Vec<WireEntry>) -> Vec<OutputEntry> {
+    entries.into_iter()
+      .map(|e| {
+        let id = e.file.id;
+        let handle = Arc::new(FileHandle {
+          shared: self.0.clone(),
+          generation,
+          id,
+          size: Mutex::new(None)
+        });
+        let h = handle.clone();
+        let mut file = OutputFile::new(e.file.metadata, move |offset, size| {
+          h.call(FileCall::Read { id, offset, size })
+        });
+        // open returns the size of files with dynamic size
+        if e.file.open || e.file.dynamic_size {
+          let h = handle.clone();
+          file = file.on_open(move || h.call(FileCall::Open { id }).map(|_| ()));
+        }
+        if e.file.close {
+          let h = handle.clone();
+          file = file.on_close(move || h.call(FileCall::Close { id }).map(|_| ()));
+        }
+        if e.file.dynamic_size {
+          file = file.with_dynamic_size(move || *handle.size.lock().unwrap());
+        }
+        OutputEntry::file(e.path, file)
+      })
+      .collect()
+  }
+}
+
+impl Transform for WorkerTransform {
+  fn transform(&self, inputs: &[Input]) -> anyhow::Result<Vec<OutputEntry>> {
+    let mut worker = self.0.worker.lock().unwrap();
+    let request = Request::Transform {
+      inputs: worker.register_inputs(inputs)
+    };
+    match worker.call(&request, &self.0.released)? {
+      (Response::Outputs(entries), _) => Ok(self.outputs(worker.generation, entries)),
+      _ => Err(unexpected())
+    }
+  }
+
+  fn incremental(&self) -> bool {
+    self.0.worker.lock().unwrap().incremental
+  }
+
+  fn on_change(&self, added: &[Input], removed: &[Input], modified: &[Input]) -> anyhow::Result<Option<OutputPatch>> {
+    let mut worker = self.0.worker.lock().unwrap();
+    if worker.process.is_none() {
+      // a restarted worker has no previous outputs to patch
+      return Ok(None);
+    }
+    let request = Request::OnChange {
+      added: worker.register_inputs(added),
+      removed: worker.register_inputs(removed),
+      modified: worker.register_inputs(modified)
+    };
+    match worker.call(&request, &self.0.released)? {
+      (Response::Patch(patch), _) => Ok(patch.map(|(add, remove)| OutputPatch {
+        add: self.outputs(worker.generation, add),
+        remove
+      })),
+      _ => Err(unexpected())
+    }
+  }
+}
+
+/// Load a Lua script in a worker process running `program` (a new process on reload)
+pub struct WorkerLoader {
+  pub program: PathBuf,
+  pub script: PathBuf,
+  pub options: ScriptOptions
+}
+
+impl TransformLoader for WorkerLoader {
+  fn name(&self) -> String {
+    format!("{} (worker)", self.script.display())
+  }
+
+  fn load(&self) -> anyhow::Result<Arc<dyn Transform>> {
+    let mut worker = Worker {
+      program: self.program.clone(),
+      script: self.script.clone(),
+      options: self.options.clone(),
+      process: None,
+      generation: 0,
+      incremental: false,
+      inputs: Inputs::new(),
+      next_input: 0
+    };
+    worker.start()?;
+    Ok(Arc::new(WorkerTransform(Arc::new(Shared {
+      worker: Mutex::new(worker),
+      released: Mutex::default()
+    }))))
+  }
+}
+
+// os.exit is not in Luau
+#[cfg(all(test, not(feature = "luau")))]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  const SCRIPT: &str = r#"
+    local M = {}
+
+    function M.transform(inputs)
+      return {
+        { path = "ok", metadata = { size = 1 }, read = function() return "a" end },
+        { path = "crash", metadata = { size = 1 }, read = function() os.exit(1) end }
+      }
+    end
+
+    return M
+  "#;
+
+  // program built next to the test binary (not built with `cargo test --lib`)
+  fn program() -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    let program = exe.parent()?.parent()?.join("transformfs");
+    program.is_file().then_some(program)
+  }
+
+  fn read(outputs: &[OutputEntry], path: &str) -> anyhow::Result<Vec<u8>> {
+    let entry = outputs.iter().find(|e| e.path == path).unwrap();
+    let OutputContent::File(file) = &entry.content else {
+      panic!("{} is not a file", path);
+    };
+    (file.read)(0, 1)
+  }
+
+  fn crashed(result: anyhow::Result<Vec<u8>>) -> bool {
+    result.is_err_and(|e| e.is::<WorkerCrashed>())
+  }
+
+  #[test]
+  fn restart_after_crash() {
+    let Some(program) = program() else {
+      eprintln!("transformfs binary not found, skipping");
+      return;
+    };
+    let dir = std::env::temp_dir().join(format!("transformfs-worker-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("crash.lua");
+    fs::write(&script, SCRIPT).unwrap();
+
+    let loader = WorkerLoader { program, script, options: ScriptOptions::default() };
+    let transform = loader.load().unwrap();
+    let outputs = transform.transform(&[]).unwrap();
+    assert_eq!(read(&outputs, "ok").unwrap(), b"a");
+    assert!(crashed(read(&outputs, "crash")));
+    // outputs of the crashed worker are stale
+    assert!(crashed(read(&outputs, "ok")));
+
+    // restarted on the next call
+    let outputs = transform.transform(&[]).unwrap();
+    assert_eq!(read(&outputs, "ok").unwrap(), b"a");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}